@@ -0,0 +1,294 @@
+use flate2::read::DeflateDecoder;
+use image::io::Reader as ImageReader;
+use image::{DynamicImage, ImageFormat};
+use log::warn;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use zip::CompressionMethod;
+
+use crate::librote::error;
+use crate::librote::order;
+use crate::librote::SkippedFile;
+
+// Bytes read from the start of a file to recognise its format
+const MAGIC_LENGTH: u64 = 32;
+
+// An archive behaves like a directory: `book.cbz/0001.jpg` is an entry of `book.cbz`
+#[derive(Clone, Copy)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+}
+
+fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let extension = path.extension().and_then(OsStr::to_str)?.to_lowercase();
+    match extension.as_str() {
+        "zip" | "cbz" => Some(ArchiveKind::Zip),
+        "tar" | "cbt" => Some(ArchiveKind::Tar),
+        _ => None,
+    }
+}
+
+pub fn is_archive(path: &str) -> bool {
+    let path = Path::new(path);
+    archive_kind(path).is_some() && path.is_file()
+}
+
+// The archive and the entry name when `path` points inside an archive
+fn split_archive_path(path: &str) -> Option<(&Path, ArchiveKind, String)> {
+    let path = Path::new(path);
+    path.ancestors().skip(1).find_map(|ancestor| {
+        let kind = archive_kind(ancestor)?;
+        if !ancestor.is_file() {
+            return None;
+        }
+        let entry = path
+            .strip_prefix(ancestor)
+            .ok()?
+            .to_str()?
+            .replace('\\', "/");
+        Some((ancestor, kind, entry))
+    })
+}
+
+fn read_magic<R: Read>(reader: R) -> io::Result<Vec<u8>> {
+    let mut magic = Vec::new();
+    reader.take(MAGIC_LENGTH).read_to_end(&mut magic)?;
+    Ok(magic)
+}
+
+// Names and first bytes of the files in an archive, in archive order, or why
+// an entry cannot be read
+type Entries = Vec<(String, Result<Vec<u8>, String>)>;
+
+fn entries(archive: &Path, kind: ArchiveKind) -> Result<Entries, error::Error> {
+    let file = File::open(archive)?;
+    let mut entries = Vec::new();
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(file)?;
+            for i in 0..zip.len() {
+                // the raw entry can be listed whatever its compression
+                let (name, compression) = {
+                    let entry = zip.by_index_raw(i)?;
+                    if !entry.is_file() {
+                        continue;
+                    }
+                    (String::from(entry.name()), entry.compression())
+                };
+                let magic = match compression {
+                    CompressionMethod::Stored | CompressionMethod::Deflated => zip
+                        .by_index(i)
+                        .map_err(|e| e.to_string())
+                        .and_then(|mut entry| read_magic(&mut entry).map_err(|e| e.to_string())),
+                    _ => Err(String::from("unsupported compression method")),
+                };
+                entries.push((name, magic));
+            }
+        }
+        ArchiveKind::Tar => {
+            let mut tar = tar::Archive::new(file);
+            for entry in tar.entries_with_seek()? {
+                let mut entry = entry?;
+                if entry.header().entry_type().is_file() {
+                    let name = entry.path()?.to_string_lossy().into_owned();
+                    let magic = read_magic(&mut entry).map_err(|e| e.to_string());
+                    entries.push((name, magic));
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Where the bytes of an entry start in the archive, how many there are and whether they are deflated
+#[derive(Clone, Copy)]
+struct EntryLocation {
+    offset: u64,
+    size: u64,
+    deflated: bool,
+}
+
+type EntryIndex = HashMap<String, EntryLocation>;
+
+fn index_entries(archive: &Path, kind: ArchiveKind) -> Result<EntryIndex, error::Error> {
+    let file = File::open(archive)?;
+    let mut index = HashMap::new();
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(file)?;
+            for i in 0..zip.len() {
+                let entry = zip.by_index_raw(i)?;
+                let deflated = match entry.compression() {
+                    CompressionMethod::Stored => false,
+                    CompressionMethod::Deflated => true,
+                    // listed as skipped, it is never read
+                    _ => continue,
+                };
+                if entry.is_file() {
+                    let location = EntryLocation {
+                        offset: entry.data_start(),
+                        size: entry.compressed_size(),
+                        deflated,
+                    };
+                    index.insert(String::from(entry.name()), location);
+                }
+            }
+        }
+        ArchiveKind::Tar => {
+            let mut tar = tar::Archive::new(file);
+            for entry in tar.entries_with_seek()? {
+                let entry = entry?;
+                if entry.header().entry_type().is_file() {
+                    let location = EntryLocation {
+                        offset: entry.raw_file_position(),
+                        size: entry.size(),
+                        deflated: false,
+                    };
+                    index.insert(entry.path()?.to_string_lossy().into_owned(), location);
+                }
+            }
+        }
+    }
+    Ok(index)
+}
+
+// Entries of every archive read so far, a read seeks straight to its entry
+// instead of walking (a tar) or parsing (a zip) the archive again
+fn entry_index(archive: &Path, kind: ArchiveKind) -> Result<Arc<EntryIndex>, error::Error> {
+    static INDEXES: OnceLock<Mutex<HashMap<PathBuf, Arc<EntryIndex>>>> = OnceLock::new();
+    let mut indexes = INDEXES.get_or_init(Default::default).lock().unwrap();
+    if let Some(index) = indexes.get(archive) {
+        return Ok(Arc::clone(index));
+    }
+    let index = Arc::new(index_entries(archive, kind)?);
+    indexes.insert(archive.to_path_buf(), Arc::clone(&index));
+    Ok(index)
+}
+
+fn read_entry(archive: &Path, kind: ArchiveKind, name: &str) -> Result<Vec<u8>, error::Error> {
+    let location = *entry_index(archive, kind)?.get(name).ok_or_else(|| {
+        error::Error::ArchiveEntryErr(archive.display().to_string(), String::from(name))
+    })?;
+    let mut file = File::open(archive)?;
+    file.seek(SeekFrom::Start(location.offset))?;
+    let raw = file.take(location.size);
+    let mut data = Vec::new();
+    if location.deflated {
+        DeflateDecoder::new(raw).read_to_end(&mut data)?;
+    } else {
+        BufReader::new(raw).read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+// Why a file is not a page, `None` when its extension and content agree on a readable format
+fn rejection(path: &str, magic: &[u8]) -> Option<String> {
+    let format = match ImageFormat::from_path(path) {
+        Ok(format) if format.can_read() => format,
+        _ => return Some(String::from("not an image file extension")),
+    };
+    match image::guess_format(magic) {
+        Ok(content) if content == format => None,
+        Ok(content) => Some(format!("{:?} content in a {:?} file", content, format)),
+        // TGA has no magic bytes
+        Err(_) if format == ImageFormat::Tga => None,
+        Err(_) => Some(String::from("not an image")),
+    }
+}
+
+/// The pages of an input and the files that were left out
+pub struct Listing {
+    pub pages: Vec<String>,
+    pub skipped: Vec<SkippedFile>,
+}
+
+impl Listing {
+    fn push(&mut self, path: String, rejection: Option<String>) {
+        match rejection {
+            None => self.pages.push(path),
+            Some(reason) => {
+                warn!("Skipping {}: {}", path, reason);
+                self.skipped.push(SkippedFile { path, reason });
+            }
+        }
+    }
+}
+
+/// Every page of an image directory or archive, naturally sorted.
+///
+/// Files are pages when both their extension and their first bytes are those
+/// of a readable image, anything else is skipped with a warning. Archives are
+/// always listed whole, directories only descend into subdirectories with
+/// `recursive`.
+pub fn list_pages(input: &str, recursive: bool) -> Listing {
+    let mut listing = Listing {
+        pages: Vec::new(),
+        skipped: Vec::new(),
+    };
+    match archive_kind(Path::new(input)) {
+        Some(kind) if is_archive(input) => {
+            let mut entries = entries(Path::new(input), kind).expect("Could not read archive");
+            entries.sort_by(|(a, _), (b, _)| order::natural_cmp(a, b));
+            for (name, magic) in entries {
+                let path = format!("{}/{}", input, name);
+                let rejection = match magic {
+                    Ok(magic) => rejection(&path, &magic),
+                    Err(reason) => Some(reason),
+                };
+                listing.push(path, rejection);
+            }
+        }
+        _ => {
+            for path in order::list_directory(input, recursive) {
+                let rejection = if Path::new(&path).is_dir() {
+                    if recursive {
+                        // its files are listed on their own
+                        continue;
+                    }
+                    Some(String::from("subdirectory, use --recursive to include it"))
+                } else {
+                    match File::open(&path).and_then(read_magic) {
+                        Ok(magic) => rejection(&path, &magic),
+                        Err(e) => Some(e.to_string()),
+                    }
+                };
+                listing.push(path, rejection);
+            }
+        }
+    }
+    listing
+}
+
+/// Read a file, from inside its archive when the path goes through one
+pub fn read(path: &str) -> Result<Vec<u8>, error::Error> {
+    match split_archive_path(path) {
+        Some((archive, kind, name)) => read_entry(archive, kind, &name),
+        None => Ok(fs::read(path)?),
+    }
+}
+
+pub fn image_dimensions(path: &str) -> Result<(u32, u32), error::Error> {
+    if split_archive_path(path).is_none() {
+        return Ok(image::image_dimensions(path)?);
+    }
+    decode_dimensions(path, &read(path)?)
+}
+
+/// Decode the bytes `read` returned for `path`
+pub fn decode_image(path: &str, data: &[u8]) -> Result<DynamicImage, error::Error> {
+    Ok(image::load_from_memory_with_format(
+        data,
+        ImageFormat::from_path(path)?,
+    )?)
+}
+
+/// Dimensions of the image in the bytes `read` returned for `path`, only its header is decoded
+pub fn decode_dimensions(path: &str, data: &[u8]) -> Result<(u32, u32), error::Error> {
+    let reader = ImageReader::with_format(Cursor::new(data), ImageFormat::from_path(path)?);
+    Ok(reader.into_dimensions()?)
+}
@@ -0,0 +1,188 @@
+use log::{debug, warn};
+use std::fmt;
+
+// Used when the pages of a book do not split into clear clusters
+pub const DEFAULT_IMAGE_THRESHOLD: u32 = 750;
+
+// Otsu separability (between-class variance / total variance) that a split must
+// reach before we trust it. A single unimodal cluster of text pages scores around
+// 0.65 - 0.75, two well separated clusters score close to 1.0
+const MIN_CONFIDENCE: f64 = 0.85;
+
+pub struct Threshold {
+    pub value: u32,
+    pub confidence: f64,
+    pub calibrated: bool,
+}
+
+impl Threshold {
+    fn fallback(value: u32, confidence: f64) -> Self {
+        Self {
+            value,
+            confidence,
+            calibrated: false,
+        }
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.calibrated {
+            write!(f, "{} (confidence {:.2})", self.value, self.confidence)
+        } else {
+            write!(
+                f,
+                "{} (default, no clear cluster found, best confidence {:.2})",
+                self.value, self.confidence
+            )
+        }
+    }
+}
+
+struct Split {
+    // values <= cut belong to the lower class
+    cut: u32,
+    confidence: f64,
+    upper: Vec<u32>,
+}
+
+/// Find the text/image cut from the histogram medians of the non blank pages.
+///
+/// The pages are first split in two with Otsu's method. The bigger class is the
+/// text cluster (a book is mostly text). When the smaller class is above it,
+/// those are the illustrations, otherwise the text cluster is split once more
+/// to look for them.
+pub fn calibrate(medians: &[u32]) -> Threshold {
+    let mut sorted = medians.to_vec();
+    sorted.sort_unstable();
+
+    let split = match otsu(&sorted) {
+        Some(split) if split.confidence >= MIN_CONFIDENCE => split,
+        other => {
+            let confidence = other.map_or(0.0, |split| split.confidence);
+            warn!(
+                "Could not find separate page clusters (confidence {:.2}), using the default threshold",
+                confidence
+            );
+            return Threshold::fallback(DEFAULT_IMAGE_THRESHOLD, confidence);
+        }
+    };
+    debug!(
+        "First split at {} ({} pages below, {} pages above)",
+        split.cut,
+        sorted.len() - split.upper.len(),
+        split.upper.len()
+    );
+
+    if split.upper.len() * 2 > sorted.len() {
+        // the smaller lower class are unusually sparse pages, look for images among the text pages
+        match otsu(&split.upper) {
+            Some(inner) if inner.confidence >= MIN_CONFIDENCE => Threshold {
+                value: inner.cut,
+                confidence: inner.confidence,
+                calibrated: true,
+            },
+            inner => Threshold::fallback(
+                DEFAULT_IMAGE_THRESHOLD.max(split.cut),
+                inner.map_or(0.0, |inner| inner.confidence),
+            ),
+        }
+    } else {
+        // the smaller upper class are the images
+        Threshold {
+            value: split.cut,
+            confidence: split.confidence,
+            calibrated: true,
+        }
+    }
+}
+
+// Two-class Otsu over sorted values, the cut is placed halfway between both classes
+fn otsu(sorted: &[u32]) -> Option<Split> {
+    if sorted.len() < 2 {
+        return None;
+    }
+    let n = sorted.len() as f64;
+    let total: f64 = sorted.iter().map(|&v| v as f64).sum();
+    let mean = total / n;
+    let total_variance: f64 = sorted
+        .iter()
+        .map(|&v| (v as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    if total_variance == 0.0 {
+        return None;
+    }
+
+    // (lower class size, between-class variance)
+    let mut best = (0, 0.0);
+    let mut lower_sum = 0.0;
+    for k in 1..sorted.len() {
+        lower_sum += sorted[k - 1] as f64;
+        // only split between distinct values
+        if sorted[k - 1] == sorted[k] {
+            continue;
+        }
+        let w0 = k as f64 / n;
+        let w1 = 1.0 - w0;
+        let mu0 = lower_sum / k as f64;
+        let mu1 = (total - lower_sum) / (n - k as f64);
+        let between_variance = w0 * w1 * (mu0 - mu1).powi(2);
+        if between_variance > best.1 {
+            best = (k, between_variance);
+        }
+    }
+
+    let (k, between_variance) = best;
+    Some(Split {
+        cut: (sorted[k - 1] + sorted[k]) / 2,
+        confidence: between_variance / total_variance,
+        upper: sorted[k..].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn otsu_cuts_halfway_between_the_classes() {
+        let split = otsu(&[100, 100, 110, 200, 210, 210]).unwrap();
+        assert_eq!(split.cut, 155);
+        assert_eq!(split.upper, [200, 210, 210]);
+        assert!(split.confidence > 0.95);
+        assert!(otsu(&[100]).is_none());
+        assert!(otsu(&[100, 100, 100]).is_none());
+    }
+
+    #[test]
+    fn separates_text_from_images() {
+        // 40 text pages and 6 illustrations
+        let mut medians: Vec<u32> = (0..40).map(|i| 300 + (i % 10) * 10).collect();
+        medians.extend((0..6).map(|i| 1600 + i * 50));
+        let threshold = calibrate(&medians);
+        assert!(threshold.calibrated);
+        assert!(threshold.value > 390 && threshold.value < 1600);
+        assert!(threshold.confidence >= MIN_CONFIDENCE);
+    }
+
+    #[test]
+    fn falls_back_without_clusters() {
+        let medians: Vec<u32> = (0..50).map(|i| 300 + i * 2).collect();
+        let threshold = calibrate(&medians);
+        assert!(!threshold.calibrated);
+        assert_eq!(threshold.value, DEFAULT_IMAGE_THRESHOLD);
+        assert!(threshold.confidence < MIN_CONFIDENCE);
+    }
+
+    #[test]
+    fn looks_for_images_above_sparse_pages() {
+        // chapter titles and near blank pages sit far below the text
+        let mut medians = vec![0; 10];
+        medians.extend((0..40).map(|i| 1000 + (i % 5) * 5));
+        medians.extend([1400; 4]);
+        let threshold = calibrate(&medians);
+        assert!(threshold.calibrated);
+        assert!(threshold.value > 1020 && threshold.value < 1400);
+    }
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::librote::error;
+
+pub const CHUNKS_PATH: &str = "chunks.toml";
+const CHUNKS_VERSION: u32 = 1;
+
+/// A page as embedded in a chunk
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChunkPage {
+    // number in the marker following the page
+    pub marker: u32,
+    // page path in the plan
+    pub path: String,
+    // file the image was read from, the processed page if there is one
+    pub image_path: String,
+    // bytes of the image in the chunk
+    pub size: u64,
+    // of the file the image was read from
+    pub md5: String,
+    // what was done to the page to fit the chunk
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shrunk: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Chunk {
+    pub index: u32,
+    pub pdf_path: String,
+    pub size: u64,
+    pub md5: String,
+    // in reading order
+    #[serde(rename = "page")]
+    pub pages: Vec<ChunkPage>,
+}
+
+impl Chunk {
+    /// File the OCR result of the chunk is downloaded to
+    pub fn ocr_path(&self) -> String {
+        format!("ocr_{:02}.html", self.index)
+    }
+}
+
+/// The chunks written by `rote ocr` and the pages in each of them
+#[derive(Serialize, Deserialize)]
+pub struct ChunkManifest {
+    version: u32,
+    #[serde(default, rename = "chunk")]
+    chunks: Vec<Chunk>,
+}
+
+impl ChunkManifest {
+    pub fn new(chunks: Vec<Chunk>) -> Self {
+        Self {
+            version: CHUNKS_VERSION,
+            chunks,
+        }
+    }
+    pub fn load(path: &str) -> Self {
+        let raw_manifest = fs::read_to_string(path)
+            .unwrap_or_else(|_| panic!("Could not read `{}`, run `rote ocr` first", path));
+        toml::from_str(&raw_manifest).expect("Could not parse the chunk manifest")
+    }
+    pub fn save(&self, path: &str) -> Result<(), error::Error> {
+        let toml = toml::to_string(self).unwrap();
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, toml)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+}
@@ -7,6 +7,7 @@ use std::fmt::Write;
 use std::fs::{self, OpenOptions};
 use std::path::Path;
 
+use crate::librote::archive;
 use crate::librote::error;
 
 #[derive(Deserialize)]
@@ -64,7 +65,7 @@ pub fn gen_epub(epub_plan_path: &str, image_path: &str, output_epub_path: &str)
     let fit_style = fs::read_to_string("fit-style.css").expect("Could not read `fit-style.css`");
     let keep_space_img = fs::read("keep-space.jpg").expect("Could not read `keep-space.jpg");
     let cover_image_path = format!("{}/{}", image_path, &epub_plan.cover_image);
-    let cover_image = fs::read(&cover_image_path).expect("Could not read cover image");
+    let cover_image = archive::read(&cover_image_path).expect("Could not read cover image");
     let cover_image_mime_type = get_image_mime_type(&cover_image_path);
 
     let unprocessed_raw = fs::read_to_string(&epub_plan.raw).expect("Could not read `raw`");
@@ -679,7 +680,7 @@ fn add_title_page<'a, Z: Zip>(
 ) -> Result<&'a mut EpubBuilder<Z>, error::Error> {
     let title_page_content = generate_preface_image_xhtml(epub_plan, img_name);
     let img_full_path = format!("{}/{}", img_path, img_name);
-    let title_page_img = fs::read(&img_full_path).expect("Could not read title page image");
+    let title_page_img = archive::read(&img_full_path).expect("Could not read title page image");
     epub.add_resource(
         format!("image/{}", img_name),
         title_page_img.as_slice(),
@@ -700,7 +701,7 @@ fn add_gaiji_image<'a, Z: Zip>(
     img_name: &'a str,
 ) -> Result<&'a mut EpubBuilder<Z>, error::Error> {
     let img_full_path = format!("{}/{}", img_path, img_name);
-    let img = fs::read(&img_full_path).expect("Could not read image");
+    let img = archive::read(&img_full_path).expect("Could not read image");
     epub.add_resource(
         format!("image/{}", img_name),
         img.as_slice(),
@@ -719,7 +720,7 @@ fn add_normal_image<'a, Z: Zip>(
 ) -> Result<&'a mut EpubBuilder<Z>, error::Error> {
     let img_content = generate_image_xhtml(epub_plan, img_name);
     let img_full_path = format!("{}/{}", img_path, img_name);
-    let img = fs::read(&img_full_path).expect("Could not read image");
+    let img = archive::read(&img_full_path).expect("Could not read image");
     epub.add_resource(
         format!("image/{}", img_name),
         img.as_slice(),
@@ -742,7 +743,7 @@ fn add_colophon_image<'a, Z: Zip>(
 ) -> Result<&'a mut EpubBuilder<Z>, error::Error> {
     let img_content = generate_image_xhtml(epub_plan, img_name);
     let img_full_path = format!("{}/{}", img_path, img_name);
-    let img = fs::read(&img_full_path).expect("Could not read image");
+    let img = archive::read(&img_full_path).expect("Could not read image");
     epub.add_resource(
         format!("image/{}", img_name),
         img.as_slice(),
@@ -766,7 +767,7 @@ fn add_preface_image<'a, Z: Zip>(
 ) -> Result<&'a mut EpubBuilder<Z>, error::Error> {
     let preface_img_content = generate_preface_image_xhtml(epub_plan, img_name);
     let img_full_path = format!("{}/{}", img_path, img_name);
-    let preface_img = fs::read(&img_full_path).expect("Could not read preface page image");
+    let preface_img = archive::read(&img_full_path).expect("Could not read preface page image");
     epub.add_resource(
         format!("image/{}", img_name),
         preface_img.as_slice(),
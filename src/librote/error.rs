@@ -5,4 +5,16 @@ pub enum Error {
     GlobErr(#[from] glob::GlobError),
     #[error("ImageError when translating image to luma8")]
     ImageErr(#[from] image::ImageError),
+    #[error("IO Error: {0}")]
+    IoErr(#[from] std::io::Error),
+    #[error("Zip Error: {0}")]
+    ZipErr(#[from] zip::result::ZipError),
+    #[error("Could not find `{1}` in archive `{0}`")]
+    ArchiveEntryErr(String, String),
+    #[error("`{0}` and `{1}` would both be written as `{2}`, rename one of them")]
+    NameCollisionErr(String, String, String),
+    #[error("PDF Error: {0}")]
+    PdfErr(#[from] lopdf::Error),
+    #[error("Could not extract the image of PDF page {0}: {1}")]
+    PdfImageErr(u32, String),
 }
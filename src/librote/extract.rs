@@ -0,0 +1,454 @@
+use flate2::read::ZlibDecoder;
+use image::{DynamicImage, GrayImage, RgbImage};
+use log::{debug, info, warn};
+use lopdf::{Dictionary, Document, Object, Stream};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use crate::librote::error;
+use crate::librote::preprocess;
+
+// Image filters `image` cannot decode, with the extension their stream is kept under
+const UNDECODED_FILTERS: [(&str, &str); 3] = [
+    ("CCITTFaxDecode", "ccitt"),
+    ("JBIG2Decode", "jb2"),
+    ("JPXDecode", "jp2"),
+];
+
+pub fn is_pdf(path: &str) -> bool {
+    let path = Path::new(path);
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"))
+        && path.is_file()
+}
+
+/// Directory the pages of a PDF are extracted to, `book.pdf` gives `book_pages`
+pub fn extraction_dir(pdf: &str) -> String {
+    let stem = Path::new(pdf).file_stem().and_then(OsStr::to_str).unwrap();
+    format!("{}_pages", stem)
+}
+
+/// The image directory (or archive) behind an input.
+///
+/// A PDF is extracted on first use, later stages reuse the extracted pages so
+/// the paths in the plan stay valid. Pages are extracted to a temporary
+/// directory renamed once every page is written, so an interrupted extraction
+/// starts over instead of leaving a book with missing pages.
+pub fn resolve_input(input: &str) -> Result<String, error::Error> {
+    if !is_pdf(input) {
+        return Ok(String::from(input));
+    }
+    let output_dir = extraction_dir(input);
+    if Path::new(&output_dir).is_dir() {
+        info!("Using the pages already extracted to `{}`", output_dir);
+    } else {
+        let partial_dir = format!("{}.partial", output_dir);
+        if Path::new(&partial_dir).exists() {
+            fs::remove_dir_all(&partial_dir)?;
+        }
+        let (count, skipped) = extract_images(input, &partial_dir)?;
+        fs::rename(&partial_dir, &output_dir)?;
+        info!(
+            "Extracted {} pages from `{}` to `{}`",
+            count, input, output_dir
+        );
+        if skipped > 0 {
+            warn!(
+                "{} page(s) have no image or could not be decoded and are skipped, convert them by hand to include them",
+                skipped
+            );
+        }
+    }
+    Ok(output_dir)
+}
+
+fn dereference<'a>(document: &'a Document, object: &'a Object) -> Option<&'a Object> {
+    document.dereference(object).ok().map(|(_, object)| object)
+}
+
+fn integer(document: &Document, dict: &Dictionary, key: &[u8]) -> Option<i64> {
+    dereference(document, dict.get(key).ok()?)?.as_i64().ok()
+}
+
+fn unsupported(page: u32, reason: String) -> error::Error {
+    error::Error::PdfImageErr(page, reason)
+}
+
+// Every image XObject reachable from a resource dictionary, forms included
+fn collect_images<'a>(
+    document: &'a Document,
+    resources: &'a Dictionary,
+    images: &mut Vec<&'a Stream>,
+) {
+    let xobjects = match resources
+        .get(b"XObject")
+        .ok()
+        .and_then(|xobjects| dereference(document, xobjects))
+        .and_then(|xobjects| xobjects.as_dict().ok())
+    {
+        Some(xobjects) => xobjects,
+        None => return,
+    };
+    for (_, xobject) in xobjects.iter() {
+        let stream =
+            match dereference(document, xobject).and_then(|xobject| xobject.as_stream().ok()) {
+                Some(stream) => stream,
+                None => continue,
+            };
+        match stream.dict.get(b"Subtype").and_then(Object::as_name_str) {
+            Ok("Image") => images.push(stream),
+            Ok("Form") => {
+                if let Some(form_resources) = stream
+                    .dict
+                    .get(b"Resources")
+                    .ok()
+                    .and_then(|resources| dereference(document, resources))
+                    .and_then(|resources| resources.as_dict().ok())
+                {
+                    collect_images(document, form_resources, images);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn page_images(document: &Document, page_id: lopdf::ObjectId) -> Vec<&Stream> {
+    let mut images = Vec::new();
+    let (resources, resource_ids) = document.get_page_resources(page_id);
+    if let Some(resources) = resources {
+        collect_images(document, resources, &mut images);
+    }
+    for id in resource_ids {
+        if let Ok(resources) = document.get_dictionary(id) {
+            collect_images(document, resources, &mut images);
+        }
+    }
+    images
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, error::Error> {
+    let mut output = Vec::new();
+    ZlibDecoder::new(data).read_to_end(&mut output)?;
+    Ok(output)
+}
+
+// Undo the PNG predictors of a Flate stream, one filter type byte per row
+fn unpredict(
+    data: Vec<u8>,
+    params: Option<&Dictionary>,
+    page: u32,
+) -> Result<Vec<u8>, error::Error> {
+    let get = |key: &[u8], default: i64| {
+        params
+            .and_then(|params| params.get(key).ok())
+            .and_then(|value| value.as_i64().ok())
+            .unwrap_or(default)
+    };
+    let predictor = get(b"Predictor", 1);
+    if predictor < 10 {
+        return if predictor == 1 {
+            Ok(data)
+        } else {
+            Err(unsupported(page, format!("predictor {}", predictor)))
+        };
+    }
+    let bits_per_pixel = (get(b"Colors", 1) * get(b"BitsPerComponent", 8)) as usize;
+    let bytes_per_pixel = bits_per_pixel.div_ceil(8).max(1);
+    let row_length = (get(b"Columns", 1) as usize * bits_per_pixel).div_ceil(8);
+
+    let mut output: Vec<u8> = Vec::with_capacity(data.len());
+    let mut previous = vec![0u8; row_length];
+    for row in data.chunks(row_length + 1) {
+        if row.len() < row_length + 1 {
+            break;
+        }
+        let (filter, row) = (row[0], &row[1..]);
+        let mut current = row.to_vec();
+        for i in 0..row_length {
+            let left = if i >= bytes_per_pixel {
+                current[i - bytes_per_pixel]
+            } else {
+                0
+            };
+            let up = previous[i];
+            let up_left = if i >= bytes_per_pixel {
+                previous[i - bytes_per_pixel]
+            } else {
+                0
+            };
+            let prediction = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => {
+                    let p = left as i16 + up as i16 - up_left as i16;
+                    let (pa, pb, pc) = (
+                        (p - left as i16).abs(),
+                        (p - up as i16).abs(),
+                        (p - up_left as i16).abs(),
+                    );
+                    if pa <= pb && pa <= pc {
+                        left
+                    } else if pb <= pc {
+                        up
+                    } else {
+                        up_left
+                    }
+                }
+                other => return Err(unsupported(page, format!("PNG filter type {}", other))),
+            };
+            current[i] = current[i].wrapping_add(prediction);
+        }
+        output.extend_from_slice(&current);
+        previous = current;
+    }
+    Ok(output)
+}
+
+// Number of components of a colour space, and the palette of an indexed one
+fn color_space(
+    document: &Document,
+    object: &Object,
+    page: u32,
+) -> Result<(usize, Option<Vec<u8>>), error::Error> {
+    let object = dereference(document, object).unwrap_or(object);
+    if let Ok(name) = object.as_name_str() {
+        return match name {
+            "DeviceGray" | "CalGray" => Ok((1, None)),
+            "DeviceRGB" | "CalRGB" => Ok((3, None)),
+            "DeviceCMYK" => Ok((4, None)),
+            other => Err(unsupported(page, format!("colour space {}", other))),
+        };
+    }
+    let array = object
+        .as_array()
+        .map_err(|_| unsupported(page, String::from("colour space")))?;
+    match array.first().and_then(|family| family.as_name_str().ok()) {
+        Some("ICCBased") => {
+            let profile = array
+                .get(1)
+                .and_then(|profile| dereference(document, profile))
+                .and_then(|profile| profile.as_stream().ok())
+                .ok_or_else(|| unsupported(page, String::from("ICC profile")))?;
+            let components = integer(document, &profile.dict, b"N").unwrap_or(3);
+            Ok((components as usize, None))
+        }
+        Some("CalGray") => Ok((1, None)),
+        Some("CalRGB") | Some("Lab") => Ok((3, None)),
+        Some("Indexed") => {
+            let (base, _) = color_space(
+                document,
+                array
+                    .get(1)
+                    .ok_or_else(|| unsupported(page, String::from("indexed colour space")))?,
+                page,
+            )?;
+            let lookup = array
+                .get(3)
+                .and_then(|lookup| dereference(document, lookup))
+                .ok_or_else(|| unsupported(page, String::from("indexed colour space")))?;
+            let palette = match lookup {
+                Object::String(bytes, _) => bytes.clone(),
+                Object::Stream(stream) => match stream.filters() {
+                    Ok(filters) if filters == ["FlateDecode"] => inflate(&stream.content)?,
+                    Ok(_) => return Err(unsupported(page, String::from("palette filter"))),
+                    Err(_) => stream.content.clone(),
+                },
+                _ => return Err(unsupported(page, String::from("palette"))),
+            };
+            // the palette is expanded to RGB (or grey) samples
+            Ok((base, Some(palette)))
+        }
+        Some(other) => Err(unsupported(page, format!("colour space {}", other))),
+        None => Err(unsupported(page, String::from("colour space"))),
+    }
+}
+
+// Rebuild a raw (already decompressed) image stream as a pixel image
+fn decode_raw(
+    document: &Document,
+    stream: &Stream,
+    data: &[u8],
+    page: u32,
+) -> Result<DynamicImage, error::Error> {
+    let dict = &stream.dict;
+    let width = integer(document, dict, b"Width").unwrap_or(0) as u32;
+    let height = integer(document, dict, b"Height").unwrap_or(0) as u32;
+    let bits = integer(document, dict, b"BitsPerComponent").unwrap_or(8) as usize;
+    if ![1, 2, 4, 8].contains(&bits) {
+        return Err(unsupported(page, format!("{} bits per component", bits)));
+    }
+    let (components, palette) = match dict.get(b"ColorSpace") {
+        Ok(color_space_object) => color_space(document, color_space_object, page)?,
+        Err(_) => (1, None),
+    };
+    // `/Decode [1 0]` inverts a grey image, common with 1 bit scans
+    let inverted = dict
+        .get(b"Decode")
+        .ok()
+        .and_then(|decode| decode.as_array().ok())
+        .and_then(|decode| decode.first())
+        .and_then(|first| {
+            first
+                .as_i64()
+                .ok()
+                .or_else(|| first.as_float().ok().map(|f| f as i64))
+        })
+        == Some(1);
+
+    let samples_per_row = width as usize * if palette.is_some() { 1 } else { components };
+    let row_length = (samples_per_row * bits).div_ceil(8);
+    let max = (1u32 << bits) - 1;
+    let sample = |row: usize, i: usize| -> u32 {
+        let bit = i * bits;
+        let byte = data.get(row * row_length + bit / 8).copied().unwrap_or(0) as u32;
+        (byte >> (8 - bits - bit % 8)) & max
+    };
+
+    let mut pixels: Vec<u8> = Vec::with_capacity(width as usize * height as usize * 3);
+    for row in 0..height as usize {
+        for i in 0..samples_per_row {
+            let value = sample(row, i);
+            match &palette {
+                Some(palette) => {
+                    let start = value as usize * components;
+                    let entry = palette
+                        .get(start..start + components)
+                        .unwrap_or(&[0, 0, 0, 0][..components]);
+                    pixels.extend_from_slice(entry);
+                }
+                None => {
+                    let scaled = (value * 255 / max) as u8;
+                    pixels.push(if inverted { 255 - scaled } else { scaled });
+                }
+            }
+        }
+    }
+
+    let image = match components {
+        1 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        3 => RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        4 => {
+            // naive CMYK conversion, good enough for the odd colour plate
+            let rgb = pixels
+                .chunks(4)
+                .flat_map(|cmyk| {
+                    let k = 255 - cmyk[3] as u32;
+                    let channel = |c: u8| ((255 - c as u32) * k / 255) as u8;
+                    vec![channel(cmyk[0]), channel(cmyk[1]), channel(cmyk[2])]
+                })
+                .collect();
+            RgbImage::from_raw(width, height, rgb).map(DynamicImage::ImageRgb8)
+        }
+        other => return Err(unsupported(page, format!("{} colour components", other))),
+    };
+    image.ok_or_else(|| unsupported(page, String::from("truncated image data")))
+}
+
+// Write the image of a page, as-is for JPEG and as PNG for everything else.
+// Returns false when the stream could not be decoded and was kept as it is.
+fn extract_image(
+    document: &Document,
+    stream: &Stream,
+    page: u32,
+    output_dir: &Path,
+) -> Result<bool, error::Error> {
+    let filters = stream.filters().unwrap_or_default();
+    let params = stream
+        .dict
+        .get(b"DecodeParms")
+        .ok()
+        .and_then(|params| dereference(document, params))
+        .and_then(|params| params.as_dict().ok());
+
+    let mut data = stream.content.clone();
+    for (i, filter) in filters.iter().enumerate() {
+        match filter.as_str() {
+            "FlateDecode" => data = unpredict(inflate(&data)?, params, page)?,
+            "DCTDecode" if i + 1 == filters.len() => {
+                // a JPEG stream is a complete JPEG file, keep it bit for bit
+                let path = output_dir.join(format!("p{:04}.jpg", page));
+                debug!("Writing the JPEG of page {} to {:?}", page, path.display());
+                fs::write(path, data)?;
+                return Ok(true);
+            }
+            other => {
+                let extension = UNDECODED_FILTERS
+                    .iter()
+                    .find(|(filter, _)| *filter == other)
+                    .map(|(_, extension)| extension);
+                return match extension {
+                    Some(extension) if i + 1 == filters.len() => {
+                        // not a page for the later stages, so it is listed as skipped
+                        let name = format!("p{:04}.{}", page, extension);
+                        warn!(
+                            "Page {} is {} encoded and cannot be decoded, skipping it, its image is kept as `{}`",
+                            page, other, name
+                        );
+                        fs::write(output_dir.join(name), data)?;
+                        Ok(false)
+                    }
+                    _ => Err(unsupported(page, format!("{} filter", other))),
+                };
+            }
+        }
+    }
+
+    let image = decode_raw(document, stream, &data, page)?;
+    let path = output_dir.join(format!("p{:04}.png", page));
+    debug!(
+        "Writing the decoded image of page {} to {:?}",
+        page,
+        path.display()
+    );
+    preprocess::save_image(&image, &path, None)?;
+    Ok(true)
+}
+
+/// Extract the scanned image of every page of a PDF to `output_dir`.
+///
+/// JPEG (DCT) streams are copied as-is, other images are decoded and written
+/// as PNG, so nothing is lost. When a page holds several images (masks,
+/// stamps) the biggest one is the scan. CCITT, JBIG2 and JPEG 2000 images
+/// are kept undecoded and pages without an image are skipped, returns the
+/// number of pages and of those skipped.
+pub fn extract_images(pdf: &str, output_dir: &str) -> Result<(usize, usize), error::Error> {
+    let document = Document::load(pdf)?;
+    let output_dir = Path::new(output_dir);
+    fs::create_dir_all(output_dir)?;
+
+    let pages = document.get_pages();
+    let mut skipped = 0;
+    for (&page, &page_id) in &pages {
+        let scan = page_images(&document, page_id)
+            .into_iter()
+            .filter(|stream| {
+                !matches!(
+                    stream.dict.get(b"ImageMask").and_then(Object::as_bool),
+                    Ok(true)
+                )
+            })
+            .max_by_key(|stream| {
+                integer(&document, &stream.dict, b"Width").unwrap_or(0)
+                    * integer(&document, &stream.dict, b"Height").unwrap_or(0)
+            });
+        let scan = match scan {
+            Some(scan) => scan,
+            None => {
+                // a blank or text-only page, there is nothing to extract
+                warn!("Page {} has no image, skipping it", page);
+                skipped += 1;
+                continue;
+            }
+        };
+        if !extract_image(&document, scan, page, output_dir)? {
+            skipped += 1;
+        }
+    }
+    Ok((pages.len(), skipped))
+}
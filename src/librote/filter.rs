@@ -0,0 +1,196 @@
+use image::{DynamicImage, GenericImage, GrayImage, Luma, Rgba};
+use imageproc::contrast::otsu_level;
+use imageproc::region_labelling::{connected_components, Connectivity};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// Sauvola window radius as a fraction of the shorter side, about two text lines
+const SAUVOLA_RADIUS_RATIO: f64 = 0.0125;
+const SAUVOLA_MIN_RADIUS: u32 = 7;
+// Sensitivity to the local contrast and dynamic range of the standard deviation
+const SAUVOLA_K: f64 = 0.34;
+const SAUVOLA_R: f64 = 128.0;
+// Part of the pixels clipped to black and to white by the contrast stretch
+const CONTRAST_CLIP: f64 = 0.01;
+// Ink components smaller than this are specks, in pixels of a page whose shorter
+// side is 1000 pixels and scaled with the resolution. Dots of a 300 dpi scan stay above it
+const SPECK_AREA: f64 = 4.0;
+
+/// One step of the filter chain applied to text pages before OCR
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    Grayscale,
+    // stretch the tones so the ink is black and the paper white
+    Contrast,
+    // adaptive binarization, handles yellowed paper and uneven lighting
+    Sauvola,
+    // remove the specks of dust and paper grain
+    Despeckle,
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Filter::Grayscale => "grayscale",
+            Filter::Contrast => "contrast",
+            Filter::Sauvola => "sauvola",
+            Filter::Despeckle => "despeckle",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grayscale" => Ok(Filter::Grayscale),
+            "contrast" => Ok(Filter::Contrast),
+            "sauvola" => Ok(Filter::Sauvola),
+            "despeckle" => Ok(Filter::Despeckle),
+            _ => Err(format!("unknown filter {}", s)),
+        }
+    }
+}
+
+/// Parse a comma separated filter chain, `none` being the empty chain
+pub fn parse_chain(chain: &str) -> Result<Vec<Filter>, String> {
+    if chain == "none" {
+        return Ok(Vec::new());
+    }
+    chain.split(',').map(|name| name.trim().parse()).collect()
+}
+
+// Linear map of the tones so the darkest and lightest `CONTRAST_CLIP` are saturated
+fn contrast(image: &DynamicImage) -> DynamicImage {
+    let luma = image.to_luma8();
+    let mut values: Vec<u8> = luma.pixels().map(|p| p[0]).collect();
+    values.sort_unstable();
+    let clip = (values.len() as f64 * CONTRAST_CLIP) as usize;
+    let low = values[clip] as f64;
+    let high = values[values.len() - 1 - clip] as f64;
+    if high <= low {
+        return image.clone();
+    }
+    let stretch = |value: u8| ((value as f64 - low) * 255.0 / (high - low)).clamp(0.0, 255.0) as u8;
+    if image.color().has_color() {
+        let mut rgb = image.to_rgb8();
+        for pixel in rgb.pixels_mut() {
+            for channel in pixel.0.iter_mut() {
+                *channel = stretch(*channel);
+            }
+        }
+        DynamicImage::ImageRgb8(rgb)
+    } else {
+        let mut luma = luma;
+        for pixel in luma.pixels_mut() {
+            pixel[0] = stretch(pixel[0]);
+        }
+        DynamicImage::ImageLuma8(luma)
+    }
+}
+
+/// Sauvola binarization of a page.
+///
+/// A pixel is ink when it is darker than `m * (1 + k * (s / R - 1))`, with `m`
+/// and `s` the mean and standard deviation of its neighbourhood, so the
+/// threshold follows the paper colour across the page.
+pub fn sauvola(luma: &GrayImage) -> GrayImage {
+    let (width, height) = luma.dimensions();
+    let radius = ((width.min(height) as f64 * SAUVOLA_RADIUS_RATIO) as u32).max(SAUVOLA_MIN_RADIUS);
+
+    // summed area tables of the values and their squares, one row and column larger.
+    // The sums wrap on big pages, a window sum is still exact as it fits in a u32
+    let stride = width as usize + 1;
+    let mut sums = vec![0u32; stride * (height as usize + 1)];
+    let mut squares = vec![0u64; stride * (height as usize + 1)];
+    for y in 0..height as usize {
+        let (mut row_sum, mut row_square) = (0u32, 0u64);
+        for x in 0..width as usize {
+            let value = luma.get_pixel(x as u32, y as u32)[0] as u32;
+            row_sum = row_sum.wrapping_add(value);
+            row_square += (value * value) as u64;
+            sums[(y + 1) * stride + x + 1] = sums[y * stride + x + 1].wrapping_add(row_sum);
+            squares[(y + 1) * stride + x + 1] = squares[y * stride + x + 1] + row_square;
+        }
+    }
+    let window_sum = |left: usize, top: usize, right: usize, bottom: usize| {
+        sums[bottom * stride + right]
+            .wrapping_add(sums[top * stride + left])
+            .wrapping_sub(sums[top * stride + right])
+            .wrapping_sub(sums[bottom * stride + left])
+    };
+    let window_square = |left: usize, top: usize, right: usize, bottom: usize| {
+        squares[bottom * stride + right] + squares[top * stride + left]
+            - squares[top * stride + right]
+            - squares[bottom * stride + left]
+    };
+
+    GrayImage::from_fn(width, height, |x, y| {
+        let left = x.saturating_sub(radius) as usize;
+        let top = y.saturating_sub(radius) as usize;
+        let right = (x + radius + 1).min(width) as usize;
+        let bottom = (y + radius + 1).min(height) as usize;
+        let count = ((right - left) * (bottom - top)) as f64;
+        let mean = window_sum(left, top, right, bottom) as f64 / count;
+        let variance = window_square(left, top, right, bottom) as f64 / count - mean * mean;
+        let threshold = mean * (1.0 + SAUVOLA_K * (variance.max(0.0).sqrt() / SAUVOLA_R - 1.0));
+        Luma([if (luma.get_pixel(x, y)[0] as f64) <= threshold {
+            0
+        } else {
+            255
+        }])
+    })
+}
+
+// Paint the ink components too small to be text white
+fn despeckle(image: &mut DynamicImage) {
+    let luma = image.to_luma8();
+    let level = otsu_level(&luma);
+    let ink = GrayImage::from_fn(luma.width(), luma.height(), |x, y| {
+        Luma([if luma.get_pixel(x, y)[0] <= level {
+            255
+        } else {
+            0
+        }])
+    });
+    let side = luma.width().min(luma.height()) as f64 / 1000.0;
+    let min_area = (SPECK_AREA * side * side).ceil().max(2.0) as usize;
+
+    let labels = connected_components(&ink, Connectivity::Eight, Luma([0]));
+    let mut sizes: Vec<usize> = Vec::new();
+    for label in labels.pixels().map(|p| p[0] as usize) {
+        if label >= sizes.len() {
+            sizes.resize(label + 1, 0);
+        }
+        sizes[label] += 1;
+    }
+    for (x, y, label) in labels.enumerate_pixels() {
+        let label = label[0] as usize;
+        if label > 0 && sizes[label] < min_area {
+            image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+        }
+    }
+}
+
+/// Whether a chain leaves only black and white, best stored losslessly
+pub fn is_binary(chain: &[Filter]) -> bool {
+    chain.contains(&Filter::Sauvola)
+}
+
+/// Apply the filters of a chain in order
+pub fn apply(image: DynamicImage, chain: &[Filter]) -> DynamicImage {
+    chain.iter().fold(image, |image, filter| match filter {
+        Filter::Grayscale => DynamicImage::ImageLuma8(image.to_luma8()),
+        Filter::Contrast => contrast(&image),
+        Filter::Sauvola => DynamicImage::ImageLuma8(sauvola(&image.to_luma8())),
+        Filter::Despeckle => {
+            let mut image = image;
+            despeckle(&mut image);
+            image
+        }
+    })
+}
@@ -7,17 +7,20 @@ use std::thread;
 use std::time;
 use yup_oauth2::{read_application_secret, InstalledFlowAuthenticator, InstalledFlowReturnMethod};
 
+use crate::librote::chunks::ChunkManifest;
 use crate::librote::error;
 
 pub async fn upload_pdf(
     client_secret_file: &'static str,
     pid: &str,
-    num_chunk: u8,
+    manifest: &ChunkManifest,
 ) -> Result<(), error::Error> {
-    let items: Vec<u8> = (1..=num_chunk).collect();
-    let tasks: Vec<_> = items
-        .into_iter()
-        .map(|i| {
+    let tasks: Vec<_> = manifest
+        .chunks()
+        .iter()
+        .cloned()
+        .map(|chunk| {
+            let i = chunk.index;
             let parent_id = pid.to_string();
             tokio::spawn(async move {
                 let secret = read_application_secret(client_secret_file)
@@ -42,7 +45,7 @@ pub async fn upload_pdf(
                     ),
                     auth,
                 );
-                info!("Uploading `chunk_{:02}.pdf`", i);
+                info!("Uploading `{}`", chunk.pdf_path);
                 let mut create_req = File::default();
                 create_req.name = Some(format!("gd_chunk_{:02}", i));
                 create_req.parents = Some(vec![parent_id.clone()]);
@@ -56,16 +59,16 @@ pub async fn upload_pdf(
                     .ignore_default_visibility(true)
                     .enforce_single_parent(false)
                     .upload(
-                        fs::File::open(format!("chunk_{:02}.pdf", i)).unwrap(),
+                        fs::File::open(&chunk.pdf_path).unwrap(),
                         "application/pdf".parse().unwrap(),
                     )
                     .await;
                 let (_, pdf_file_resp) =
                     create_result.expect("Something went wrong when uploading pdf file");
                 debug!("{:?}", pdf_file_resp);
-                info!("Finished uploading `chunk_{:02}.pdf`", i);
+                info!("Finished uploading `{}`", chunk.pdf_path);
 
-                info!("OCR-ing `chunk_{:02}.pdf`", i);
+                info!("OCR-ing `{}`", chunk.pdf_path);
                 let pdf_file_id = pdf_file_resp
                     .id
                     .expect("pdf file_id does not exist in pdf_file_resp");
@@ -85,15 +88,15 @@ pub async fn upload_pdf(
                     .await;
                 let (_, ocr_resp) = copy_result.expect("Something went wrong when OCR pdf file");
                 debug!("{:?}", ocr_resp);
-                info!("Finished OCR `chunk_{:02}.pdf`", i);
+                info!("Finished OCR `{}`", chunk.pdf_path);
 
-                info!("Downloading OCR result of `chunk_{:02}.pdf`", i);
+                info!("Downloading OCR result of `{}`", chunk.pdf_path);
                 let ocr_file_id = ocr_resp
                     .id
                     .expect("gdocs ocr file_id does not exist in ocr_resp");
                 let mut export_req = File::default();
                 export_req.parents = Some(vec![parent_id]);
-                info!("Finished downloading OCR result of `chunk_{:02}.pdf`", i);
+                info!("Finished downloading OCR result of `{}`", chunk.pdf_path);
 
                 let export_result = hub
                     .files()
@@ -109,7 +112,7 @@ pub async fn upload_pdf(
                     .create(true)
                     .truncate(true)
                     .write(true)
-                    .open(format!("ocr_{:02}.html", i))
+                    .open(chunk.ocr_path())
                     .expect("could not create outstream to write html result");
                 let bytes = hyper::body::to_bytes(export_result.into_body())
                     .await
@@ -0,0 +1,151 @@
+use image::{GrayImage, Luma};
+use regex::Regex;
+
+// Glyphs are 5x7 cells, each cell drawn as a square of this many pixels
+const CELL: u32 = 8;
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+// Blank cells between two glyphs and around the text
+const SPACING: u32 = 1;
+const PADDING: u32 = 2;
+
+/// Text of the marker following a page, unique in a book
+pub fn text(number: u32) -> String {
+    format!("ROTE-PAGE-{:04}", number)
+}
+
+// Rows of a glyph, the 5 low bits of each from left to right
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        '0' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        _ => [0x00; 7],
+    }
+}
+
+/// The marker of a page drawn black on white with the built-in font
+pub fn render(number: u32) -> GrayImage {
+    let text = text(number);
+    let columns = text.len() as u32 * (GLYPH_WIDTH + SPACING) - SPACING + 2 * PADDING;
+    let rows = GLYPH_HEIGHT + 2 * PADDING;
+    let mut image = GrayImage::from_pixel(columns * CELL, rows * CELL, Luma([255]));
+    for (i, c) in text.chars().enumerate() {
+        let left = PADDING + i as u32 * (GLYPH_WIDTH + SPACING);
+        for (y, bits) in glyph(c).iter().enumerate() {
+            for x in 0..GLYPH_WIDTH {
+                if bits & (0x10 >> x) == 0 {
+                    continue;
+                }
+                let (cell_x, cell_y) = ((left + x) * CELL, (PADDING + y as u32) * CELL);
+                for dy in 0..CELL {
+                    for dx in 0..CELL {
+                        image.put_pixel(cell_x + dx, cell_y + dy, Luma([0]));
+                    }
+                }
+            }
+        }
+    }
+    image
+}
+
+/// Finds markers in lines of OCR text
+pub struct MarkerReader {
+    pattern: Regex,
+}
+
+impl MarkerReader {
+    pub fn new() -> Self {
+        Self {
+            // letters OCR tends to read for the digits are accepted
+            pattern: Regex::new("R[O0]TE-?PA[G6]E-?([0-9OILSBZG]{4,})").unwrap(),
+        }
+    }
+
+    /// The page number of the marker in `line`, if there is one.
+    ///
+    /// Japanese OCR may give full width characters, other dashes and spaces
+    /// between the letters, they are undone first.
+    pub fn read(&self, line: &str) -> Option<u32> {
+        let normalized: String = line
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| match c {
+                // full width forms of ASCII
+                '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap(),
+                '\u{2010}'..='\u{2015}' | '\u{2212}' | '\u{30FC}' | '_' => '-',
+                _ => c,
+            })
+            .collect::<String>()
+            .to_uppercase();
+        let digits = self.pattern.captures(&normalized)?.get(1)?.as_str();
+        digits
+            .chars()
+            .map(|c| match c {
+                'O' => '0',
+                'I' | 'L' => '1',
+                'Z' => '2',
+                'S' => '5',
+                'G' => '6',
+                'B' => '8',
+                _ => c,
+            })
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_its_own_markers() {
+        let reader = MarkerReader::new();
+        for number in [0, 1, 42, 1234, 9999, 12345] {
+            assert_eq!(reader.read(&text(number)), Some(number));
+        }
+    }
+
+    #[test]
+    fn reads_misread_digits() {
+        let reader = MarkerReader::new();
+        assert_eq!(reader.read("ROTE-PAGE-OOI2"), Some(12));
+        assert_eq!(reader.read("ROTE-PAGE-0L0S"), Some(105));
+        assert_eq!(reader.read("ROTE-PAGE-B0Z6"), Some(8026));
+        assert_eq!(reader.read("ROTE-PAGE-0G10"), Some(610));
+        assert_eq!(reader.read("R0TE-PA6E-0042"), Some(42));
+    }
+
+    #[test]
+    fn reads_japanese_ocr_output() {
+        let reader = MarkerReader::new();
+        assert_eq!(reader.read("ＲＯＴＥ－ＰＡＧＥ－００４２"), Some(42));
+        assert_eq!(reader.read("ROTE ー PAGE ー 0042"), Some(42));
+        assert_eq!(reader.read("rote_page_0042 本文"), Some(42));
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        let reader = MarkerReader::new();
+        assert_eq!(reader.read("PAGE 12"), None);
+        assert_eq!(reader.read("ROTE-PAGE-12"), None);
+        assert_eq!(reader.read("吾輩は猫である。"), None);
+    }
+}
@@ -1,37 +1,577 @@
+pub mod archive;
+pub mod calibrate;
+pub mod chunks;
 pub mod epub_gen;
 pub mod error;
+pub mod extract;
+pub mod filter;
 pub mod gdrive;
+pub mod marker;
+pub mod model;
+pub mod order;
+pub mod orientation;
 pub mod pdf;
+pub mod phash;
 pub mod plan;
+pub mod preprocess;
 pub mod process;
+pub mod progress;
+pub mod report;
+pub mod review;
+pub mod spread;
 
+use filter::Filter;
+use phash::PHash;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
 
-#[derive(Serialize, Deserialize)]
+pub const OCR_PLAN_PATH: &str = "ocr_plan.toml";
+pub const OCR_PLAN_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PageClass {
+    Text,
+    Image,
+    // colour illustration, handled like an image
+    Color,
+    Empty,
+    Ignore,
+}
+
+impl fmt::Display for PageClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PageClass::Text => "text",
+            PageClass::Image => "image",
+            PageClass::Color => "color",
+            PageClass::Empty => "empty",
+            PageClass::Ignore => "ignore",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The numbers behind the classification of a page
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PageStats {
+    pub width: u32,
+    pub height: u32,
+    // median of the luma histogram bin counts
+    pub histogram_median: u32,
+    pub mean_luma: f64,
+    // percentage of dark pixels
+    pub ink_ratio: f64,
+    // percentage of coloured pixels
+    pub color_ratio: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phash: Option<PHash>,
+}
+
+/// A rectangle in pixels of the (rotated and deskewed) page
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// An illustration found inside a text page
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Illustration {
+    // the cropped picture, for the epub
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_path: Option<String>,
+    // edit it to override, the page is blanked out there before OCR
+    pub rect: CropRect,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PageRecord {
+    pub path: String,
+    // reading order, can be edited by hand
+    pub index: usize,
+    pub class: PageClass,
+    // set when the class was corrected by hand, `plan --update` never touches it
+    #[serde(default)]
+    pub user_override: bool,
+    // page this one is a suspected rescan or double feed of, it is ignored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    // clockwise turn in degrees a sideways or upside down page needs, applied by `preprocess --rotate`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<u32>,
+    // clockwise rotation in degrees applied by `preprocess --deskew`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skew_angle: Option<f64>,
+    // normalized image used instead of `path` when generating the PDF
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processed_path: Option<String>,
+    // filter chain of this page instead of the book's, `[]` for none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filters: Option<Vec<Filter>>,
+    // clockwise degrees the page was turned (rotation and deskew) when `crop` was found
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop_angle: Option<f64>,
+    // clockwise degrees the page was turned when `illustration` was found
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub illustration_angle: Option<f64>,
+    // pictures inside a text page, found by `preprocess --illustrations`,
+    // `None` until they are looked for and `[]` when the page has none,
+    // before the tables so that `[]` can be written
+    #[serde(
+        default,
+        rename = "illustration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub illustrations: Option<Vec<Illustration>>,
+    // content box kept by `preprocess --crop`, edit it to override
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop: Option<CropRect>,
+    // missing for plans upgraded from the old three-list format
+    #[serde(default)]
+    pub stats: Option<PageStats>,
+}
+
+/// A file of the input that is not a page
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SkippedFile {
+    pub path: String,
+    pub reason: String,
+}
+
+/// The cut-offs `plan` classified the pages with
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Thresholds {
+    // histogram median above which a page is an image
+    pub image: u32,
+    // percentage of ink at or below which a page is empty
+    pub empty: f64,
+    // percentage of coloured pixels above which a page is a colour image
+    pub color: f64,
+    // Otsu separability of the calibrated `image`, missing when it was given;
+    // when too low to trust, `image` is the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_confidence: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct OcrPlan {
-    plan: Plan,
+    version: u32,
+    // applied by `preprocess` to every text page
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    filters: Vec<Filter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thresholds: Option<Thresholds>,
+    // empty arrays would be written as values after the tables, which TOML forbids
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    page: Vec<PageRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    spread: Vec<Spread>,
+    // files of the input that were not taken as pages
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    skipped: Vec<SkippedFile>,
+    // upgraded from an old plan without `page_order`, text pages are not listed
+    #[serde(skip)]
+    unordered: bool,
 }
 
 impl OcrPlan {
-    pub fn new(empty_page: Vec<String>, image_page: Vec<String>, ignore_page: Vec<String>) -> Self {
+    pub fn new(page: Vec<PageRecord>, spread: Vec<Spread>) -> Self {
         Self {
-            plan: Plan {
-                empty_page,
-                image_page,
-                ignore_page,
-            },
+            version: OCR_PLAN_VERSION,
+            filters: Vec::new(),
+            thresholds: None,
+            page,
+            spread,
+            skipped: Vec::new(),
+            unordered: false,
         }
     }
+    /// Read a plan, upgrading the old three-list format if needed
+    pub fn load(path: &str) -> Self {
+        let raw_plan = fs::read_to_string(path).expect("could not read ocr plan");
+        let value: toml::Value = toml::from_str(&raw_plan).expect("Could not read OCR plan");
+        if value.get("plan").is_some() {
+            let legacy: LegacyOcrPlan = value.try_into().expect("Could not read old OCR plan");
+            legacy.upgrade()
+        } else {
+            value.try_into().expect("Could not read OCR plan")
+        }
+    }
+    /// Write the plan atomically, so a crash never leaves a half written plan behind
+    pub fn save(&self, path: &str) -> Result<(), error::Error> {
+        let toml = toml::to_string(self).unwrap();
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, toml)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+    pub fn thresholds(&self) -> Option<&Thresholds> {
+        self.thresholds.as_ref()
+    }
+    pub fn set_thresholds(&mut self, thresholds: Thresholds) {
+        self.thresholds = Some(thresholds);
+    }
+    pub fn filters(&self) -> &[Filter] {
+        &self.filters
+    }
+    pub fn set_filters(&mut self, filters: Vec<Filter>) {
+        self.filters = filters;
+    }
+    pub fn skipped(&self) -> &[SkippedFile] {
+        &self.skipped
+    }
+    pub fn set_skipped(&mut self, skipped: Vec<SkippedFile>) {
+        self.skipped = skipped;
+    }
+    pub fn record(&self, path: &str) -> Option<&PageRecord> {
+        self.page.iter().find(|record| record.path == path)
+    }
+    pub fn record_mut(&mut self, path: &str) -> Option<&mut PageRecord> {
+        self.page.iter_mut().find(|record| record.path == path)
+    }
+    /// The image to use for a page, the preprocessed one if any
+    pub fn image_path(&self, path: &str) -> String {
+        self.record(path)
+            .and_then(|record| record.processed_path.clone())
+            .unwrap_or_else(|| String::from(path))
+    }
     pub fn ignore(&self, path: String) -> bool {
-        self.plan.empty_page.contains(&path)
-            || self.plan.image_page.contains(&path)
-            || self.plan.ignore_page.contains(&path)
+        self.record(&path)
+            .is_some_and(|record| record.class != PageClass::Text)
+    }
+    /// The pages a file stands for, the split pages if it is a spread
+    pub fn expand(&self, path: String) -> Vec<String> {
+        match self.spread.iter().find(|spread| spread.source == path) {
+            Some(spread) => spread.pages.clone(),
+            None => vec![path],
+        }
+    }
+    /// Every page in reading order.
+    ///
+    /// Plans upgraded from a format without page order fall back to the
+    /// naturally sorted content of `directory`, which may be an archive.
+    pub fn pages(&self, directory: &str) -> Vec<String> {
+        if self.unordered {
+            return archive::list_pages(directory, false)
+                .pages
+                .into_iter()
+                .flat_map(|path| self.expand(path))
+                .collect();
+        }
+        self.ordered_records()
+            .into_iter()
+            .map(|record| record.path.clone())
+            .collect()
+    }
+    /// Page records sorted by their index
+    pub fn ordered_records(&self) -> Vec<&PageRecord> {
+        let mut records: Vec<&PageRecord> = self.page.iter().collect();
+        records.sort_by_key(|record| record.index);
+        records
+    }
+    /// Merge a freshly generated plan into this one.
+    ///
+    /// Pages already known keep their (possibly hand edited) class and position
+    /// and only get fresh statistics, new pages take the fresh classification.
+    /// New pages are inserted after the page that precedes them in the fresh
+    /// order, pages whose file is gone are dropped, then every page is renumbered.
+    pub fn merge(&self, fresh: &OcrPlan) -> OcrPlan {
+        let mut merged: Vec<PageRecord> = self.page.clone();
+        merged.sort_by_key(|record| record.index);
+        // deleted or renamed files, a renamed one comes back as a new page
+        merged.retain(|known| fresh.record(&known.path).is_some());
+
+        let mut previous: Option<&str> = None;
+        for record in fresh.ordered_records() {
+            match merged.iter_mut().find(|known| known.path == record.path) {
+                Some(known) => known.stats = record.stats.clone(),
+                None => {
+                    let index = previous
+                        .and_then(|previous| merged.iter().position(|known| known.path == previous))
+                        .map_or(0, |index| index + 1);
+                    merged.insert(index, record.clone());
+                }
+            }
+            previous = Some(&record.path);
+        }
+        for (index, record) in merged.iter_mut().enumerate() {
+            record.index = index;
+        }
+
+        let mut spread = self.spread.clone();
+        spread.retain(|s| {
+            s.pages
+                .iter()
+                .any(|page| merged.iter().any(|record| record.path == *page))
+        });
+        for fresh_spread in &fresh.spread {
+            if !spread.iter().any(|s| s.source == fresh_spread.source) {
+                spread.push(fresh_spread.clone());
+            }
+        }
+        let mut merged_plan = OcrPlan::new(merged, spread);
+        merged_plan.filters = self.filters.clone();
+        merged_plan.thresholds = fresh.thresholds.clone();
+        merged_plan.skipped = fresh.skipped.clone();
+        merged_plan
+    }
+    /// Human readable changes from this plan to `other`
+    pub fn diff(&self, other: &OcrPlan) -> Vec<String> {
+        let mut changes = Vec::new();
+        let classes: HashMap<&str, PageClass> = self
+            .page
+            .iter()
+            .map(|record| (record.path.as_str(), record.class))
+            .collect();
+        let other_classes: HashMap<&str, PageClass> = other
+            .page
+            .iter()
+            .map(|record| (record.path.as_str(), record.class))
+            .collect();
+        for record in other.ordered_records() {
+            match classes.get(record.path.as_str()) {
+                None => changes.push(format!("+ {} ({})", record.path, record.class)),
+                Some(class) if *class != record.class => {
+                    changes.push(format!("~ {} ({} -> {})", record.path, class, record.class))
+                }
+                _ => (),
+            }
+        }
+        for record in self.ordered_records() {
+            if !other_classes.contains_key(record.path.as_str()) {
+                changes.push(format!("- {} ({})", record.path, record.class));
+            }
+        }
+        changes
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct Plan {
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Spread {
+    pub source: String,
+    pub gutter: u32,
+    // in reading order (right page first)
+    pub pages: Vec<String>,
+}
+
+// The format written before `version` existed: three lists of paths
+#[derive(Deserialize)]
+struct LegacyOcrPlan {
+    plan: LegacyPlan,
+}
+
+#[derive(Deserialize)]
+struct LegacyPlan {
+    #[serde(default)]
+    page_order: Vec<String>,
     empty_page: Vec<String>,
     image_page: Vec<String>,
+    #[serde(default)]
+    color_page: Vec<String>,
     ignore_page: Vec<String>,
+    #[serde(default)]
+    spread: Vec<Spread>,
+}
+
+impl LegacyOcrPlan {
+    fn upgrade(self) -> OcrPlan {
+        let plan = self.plan;
+        let unordered = plan.page_order.is_empty();
+        let mut paths = plan.page_order.clone();
+        if unordered {
+            // only the listed pages are known
+            for path in plan
+                .empty_page
+                .iter()
+                .chain(&plan.image_page)
+                .chain(&plan.color_page)
+                .chain(&plan.ignore_page)
+            {
+                if !paths.contains(path) {
+                    paths.push(path.clone());
+                }
+            }
+        }
+
+        let page = paths
+            .into_iter()
+            .enumerate()
+            .map(|(index, path)| {
+                // a hand edited `ignore_page` entry wins over the generated lists
+                let class = if plan.ignore_page.contains(&path) {
+                    PageClass::Ignore
+                } else if plan.color_page.contains(&path) {
+                    PageClass::Color
+                } else if plan.image_page.contains(&path) {
+                    PageClass::Image
+                } else if plan.empty_page.contains(&path) {
+                    PageClass::Empty
+                } else {
+                    PageClass::Text
+                };
+                PageRecord {
+                    user_override: plan.ignore_page.contains(&path),
+                    path,
+                    index,
+                    class,
+                    duplicate_of: None,
+                    rotation: None,
+                    skew_angle: None,
+                    processed_path: None,
+                    filters: None,
+                    crop: None,
+                    crop_angle: None,
+                    illustration_angle: None,
+                    stats: None,
+                    illustrations: None,
+                }
+            })
+            .collect();
+
+        let mut ocr_plan = OcrPlan::new(page, plan.spread);
+        ocr_plan.unordered = unordered;
+        ocr_plan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &str, index: usize, class: PageClass) -> PageRecord {
+        PageRecord {
+            path: String::from(path),
+            index,
+            class,
+            user_override: false,
+            duplicate_of: None,
+            rotation: None,
+            skew_angle: None,
+            processed_path: None,
+            filters: None,
+            crop_angle: None,
+            illustration_angle: None,
+            illustrations: None,
+            crop: None,
+            stats: None,
+        }
+    }
+
+    fn paths(plan: &OcrPlan) -> Vec<&str> {
+        plan.ordered_records()
+            .into_iter()
+            .map(|record| record.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn merge_keeps_hand_edited_classes() {
+        let mut edited = record("p2.jpg", 1, PageClass::Ignore);
+        edited.user_override = true;
+        let known = OcrPlan::new(
+            vec![record("p1.jpg", 0, PageClass::Text), edited],
+            Vec::new(),
+        );
+        let fresh = OcrPlan::new(
+            vec![
+                record("p1.jpg", 0, PageClass::Image),
+                record("p2.jpg", 1, PageClass::Text),
+            ],
+            Vec::new(),
+        );
+        let merged = known.merge(&fresh);
+        let p2 = merged.record("p2.jpg").unwrap();
+        assert_eq!(p2.class, PageClass::Ignore);
+        assert!(p2.user_override);
+        assert_eq!(merged.record("p1.jpg").unwrap().class, PageClass::Text);
+    }
+
+    #[test]
+    fn merge_inserts_new_pages_and_drops_removed_ones() {
+        let known = OcrPlan::new(
+            vec![
+                record("p1.jpg", 0, PageClass::Text),
+                record("p2.jpg", 1, PageClass::Text),
+                record("p3.jpg", 2, PageClass::Text),
+            ],
+            Vec::new(),
+        );
+        let fresh = OcrPlan::new(
+            vec![
+                record("p1.jpg", 0, PageClass::Text),
+                record("p1b.jpg", 1, PageClass::Image),
+                record("p3.jpg", 2, PageClass::Text),
+            ],
+            Vec::new(),
+        );
+        let merged = known.merge(&fresh);
+        assert_eq!(paths(&merged), ["p1.jpg", "p1b.jpg", "p3.jpg"]);
+        let indexes: Vec<usize> = merged.ordered_records().iter().map(|r| r.index).collect();
+        assert_eq!(indexes, [0, 1, 2]);
+        assert_eq!(merged.record("p1b.jpg").unwrap().class, PageClass::Image);
+    }
+
+    #[test]
+    fn diff_lists_added_removed_and_reclassified_pages() {
+        let before = OcrPlan::new(
+            vec![
+                record("p1.jpg", 0, PageClass::Text),
+                record("p2.jpg", 1, PageClass::Text),
+            ],
+            Vec::new(),
+        );
+        let after = OcrPlan::new(
+            vec![
+                record("p1.jpg", 0, PageClass::Image),
+                record("p3.jpg", 1, PageClass::Empty),
+            ],
+            Vec::new(),
+        );
+        assert_eq!(
+            before.diff(&after),
+            [
+                "~ p1.jpg (text -> image)",
+                "+ p3.jpg (empty)",
+                "- p2.jpg (text)"
+            ]
+        );
+        assert!(before.diff(&before).is_empty());
+    }
+
+    #[test]
+    fn upgrades_a_legacy_plan() {
+        let legacy: LegacyOcrPlan = toml::from_str(
+            r#"
+            [plan]
+            page_order = ["p1.jpg", "p2.jpg", "p3.jpg", "p4.jpg", "p5.jpg"]
+            empty_page = ["p2.jpg"]
+            image_page = ["p3.jpg", "p5.jpg"]
+            color_page = ["p4.jpg"]
+            ignore_page = ["p5.jpg"]
+            "#,
+        )
+        .unwrap();
+        let plan = legacy.upgrade();
+        assert!(!plan.unordered);
+        let classes: Vec<(&str, PageClass, bool)> = plan
+            .ordered_records()
+            .into_iter()
+            .map(|record| (record.path.as_str(), record.class, record.user_override))
+            .collect();
+        assert_eq!(
+            classes,
+            [
+                ("p1.jpg", PageClass::Text, false),
+                ("p2.jpg", PageClass::Empty, false),
+                ("p3.jpg", PageClass::Image, false),
+                ("p4.jpg", PageClass::Color, false),
+                ("p5.jpg", PageClass::Ignore, true),
+            ]
+        );
+    }
 }
@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::librote::error;
+use crate::librote::{OcrPlan, PageClass, PageStats};
+
+pub const DEFAULT_MODEL_PATH: &str = "~/.local/share/rote/model.toml";
+const MODEL_VERSION: u32 = 1;
+// A class is only predicted once this many pages of it were learned
+const MIN_CLASS_SAMPLES: usize = 5;
+const FEATURES: usize = 4;
+
+// The classes the page statistics can tell apart, ignored pages are a choice
+const LEARNED_CLASSES: [PageClass; 4] = [
+    PageClass::Text,
+    PageClass::Image,
+    PageClass::Color,
+    PageClass::Empty,
+];
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Sample {
+    class: PageClass,
+    stats: PageStats,
+}
+
+/// Pages of past plans with their final class
+#[derive(Serialize, Deserialize)]
+pub struct Model {
+    version: u32,
+    #[serde(default, rename = "sample", skip_serializing_if = "Vec::is_empty")]
+    samples: Vec<Sample>,
+}
+
+// Statistics made independent of the resolution and brought to similar ranges
+fn features(stats: &PageStats) -> [f64; FEATURES] {
+    let pixels = (stats.width as f64 * stats.height as f64).max(1.0);
+    [
+        // a flat histogram has 1/256 of the pixels in every bin
+        stats.histogram_median as f64 * 256.0 / pixels,
+        stats.mean_luma / 255.0,
+        stats.ink_ratio.ln_1p(),
+        stats.color_ratio.ln_1p(),
+    ]
+}
+
+// Blank pages share a hash, the other statistics tell them apart
+fn same_page(a: &PageStats, b: &PageStats) -> bool {
+    a.phash == b.phash
+        && a.width == b.width
+        && a.height == b.height
+        && a.histogram_median == b.histogram_median
+        && a.mean_luma == b.mean_luma
+}
+
+impl Model {
+    /// Read a model, an empty one when the file does not exist yet
+    pub fn load(path: &str) -> Self {
+        if !Path::new(path).exists() {
+            return Self {
+                version: MODEL_VERSION,
+                samples: Vec::new(),
+            };
+        }
+        let raw_model = fs::read_to_string(path).expect("Could not read the classifier model");
+        toml::from_str(&raw_model).expect("Could not parse the classifier model")
+    }
+    pub fn save(&self, path: &str) -> Result<(), error::Error> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let toml = toml::to_string(self).unwrap();
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, toml)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+    /// Add the pages of a reviewed plan, returns how many were learned.
+    ///
+    /// A page already learned (same hash and statistics) takes its new class, so
+    /// learning a plan again after more corrections does not count it twice.
+    pub fn learn(&mut self, ocr_plan: &OcrPlan) -> usize {
+        let mut learned = 0;
+        for record in ocr_plan.ordered_records() {
+            let stats = match &record.stats {
+                Some(stats) if LEARNED_CLASSES.contains(&record.class) => stats,
+                _ => continue,
+            };
+            let sample = Sample {
+                class: record.class,
+                stats: stats.clone(),
+            };
+            match self
+                .samples
+                .iter_mut()
+                .find(|sample| same_page(&sample.stats, stats))
+            {
+                Some(known) => *known = sample,
+                None => self.samples.push(sample),
+            }
+            learned += 1;
+        }
+        learned
+    }
+    /// Number of learned pages of each class
+    pub fn counts(&self) -> Vec<(PageClass, usize)> {
+        LEARNED_CLASSES
+            .iter()
+            .map(|class| {
+                let count = self
+                    .samples
+                    .iter()
+                    .filter(|sample| sample.class == *class)
+                    .count();
+                (*class, count)
+            })
+            .collect()
+    }
+    /// The classifier of the learned pages, `None` until two classes have enough of them
+    pub fn classifier(&self) -> Option<Classifier> {
+        let n = self.samples.len() as f64;
+        let all: Vec<[f64; FEATURES]> = self
+            .samples
+            .iter()
+            .map(|sample| features(&sample.stats))
+            .collect();
+        // features are standardized so none dominates the distance
+        let mut mean = [0.0; FEATURES];
+        let mut scale = [1.0; FEATURES];
+        for i in 0..FEATURES {
+            mean[i] = all.iter().map(|f| f[i]).sum::<f64>() / n;
+            let variance = all.iter().map(|f| (f[i] - mean[i]).powi(2)).sum::<f64>() / n;
+            if variance > 0.0 {
+                scale[i] = variance.sqrt();
+            }
+        }
+
+        let mut centroids = Vec::new();
+        for class in &LEARNED_CLASSES {
+            let members: Vec<&[f64; FEATURES]> = all
+                .iter()
+                .zip(&self.samples)
+                .filter(|(_, sample)| sample.class == *class)
+                .map(|(f, _)| f)
+                .collect();
+            if members.len() < MIN_CLASS_SAMPLES {
+                continue;
+            }
+            let mut centroid = [0.0; FEATURES];
+            for (i, value) in centroid.iter_mut().enumerate() {
+                let sum: f64 = members.iter().map(|f| (f[i] - mean[i]) / scale[i]).sum();
+                *value = sum / members.len() as f64;
+            }
+            centroids.push((*class, centroid));
+        }
+        if centroids.len() < 2 {
+            return None;
+        }
+        Some(Classifier {
+            mean,
+            scale,
+            centroids,
+        })
+    }
+}
+
+/// Nearest centroid classifier over the standardized page features
+pub struct Classifier {
+    mean: [f64; FEATURES],
+    scale: [f64; FEATURES],
+    centroids: Vec<(PageClass, [f64; FEATURES])>,
+}
+
+impl Classifier {
+    /// Classes with a centroid, the only ones the model can predict
+    pub fn classes(&self) -> Vec<PageClass> {
+        self.centroids.iter().map(|(class, _)| *class).collect()
+    }
+    pub fn classify(&self, stats: &PageStats) -> PageClass {
+        let page = features(stats);
+        let distance = |centroid: &[f64; FEATURES]| -> f64 {
+            (0..FEATURES)
+                .map(|i| ((page[i] - self.mean[i]) / self.scale[i] - centroid[i]).powi(2))
+                .sum()
+        };
+        self.centroids
+            .iter()
+            .map(|(class, centroid)| (*class, distance(centroid)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(class, _)| class)
+            .unwrap()
+    }
+}
@@ -0,0 +1,124 @@
+use glob::glob;
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::Chars;
+
+fn take_chunk(chars: &mut Peekable<Chars>, digit: bool) -> String {
+    let mut chunk = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_ascii_digit() != digit {
+            break;
+        }
+        chunk.push(c);
+        chars.next();
+    }
+    chunk
+}
+
+/// Compare two paths the way a human would, so that `p2.jpg` comes before `p10.jpg`
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    loop {
+        let (a_next, b_next) = match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(&a_next), Some(&b_next)) => (a_next, b_next),
+        };
+        let ordering = if a_next.is_ascii_digit() && b_next.is_ascii_digit() {
+            let a_num = take_chunk(&mut a_chars, true);
+            let b_num = take_chunk(&mut b_chars, true);
+            let a_trimmed = a_num.trim_start_matches('0');
+            let b_trimmed = b_num.trim_start_matches('0');
+            a_trimmed
+                .len()
+                .cmp(&b_trimmed.len())
+                .then_with(|| a_trimmed.cmp(b_trimmed))
+        } else {
+            let a_text = take_chunk(&mut a_chars, false);
+            let b_text = take_chunk(&mut b_chars, false);
+            if a_text.is_empty() || b_text.is_empty() {
+                // a number against text, digits sort first like in ASCII
+                a_next.cmp(&b_next)
+            } else {
+                a_text.cmp(&b_text)
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+pub fn sort_natural(paths: &mut [String]) {
+    paths.sort_by(|a, b| natural_cmp(a, b));
+}
+
+/// Every entry of a directory, naturally sorted.
+///
+/// With `recursive` the files of its subdirectories are listed as well.
+pub fn list_directory(directory: &str, recursive: bool) -> Vec<String> {
+    let pattern = if recursive { "**/*" } else { "*" };
+    let mut paths: Vec<String> = glob(&format!("{}/{}", directory, pattern))
+        .expect("Failed to read glob pattern")
+        .filter_map(|entry| entry.ok())
+        .map(|path| String::from(path.to_str().unwrap()))
+        .collect();
+    sort_natural(&mut paths);
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_numbers_by_value() {
+        assert_eq!(natural_cmp("p2.jpg", "p10.jpg"), Ordering::Less);
+        assert_eq!(natural_cmp("p10.jpg", "p9.jpg"), Ordering::Greater);
+        assert_eq!(natural_cmp("vol1/p10.jpg", "vol2/p1.jpg"), Ordering::Less);
+    }
+
+    #[test]
+    fn ignores_leading_zeros() {
+        assert_eq!(natural_cmp("p002.jpg", "p10.jpg"), Ordering::Less);
+        assert_eq!(natural_cmp("p0010.jpg", "p9.jpg"), Ordering::Greater);
+        // equal values fall back to the text so the order stays total
+        assert_eq!(natural_cmp("p01.jpg", "p1.jpg"), Ordering::Less);
+    }
+
+    #[test]
+    fn sorts_mixed_text_and_digits() {
+        let mut paths: Vec<String> = [
+            "cover.jpg",
+            "p10a.jpg",
+            "p10.jpg",
+            "p2b.jpg",
+            "10.jpg",
+            "p2a.jpg",
+        ]
+        .iter()
+        .map(|path| path.to_string())
+        .collect();
+        sort_natural(&mut paths);
+        assert_eq!(
+            paths,
+            [
+                "10.jpg",
+                "cover.jpg",
+                "p2a.jpg",
+                "p2b.jpg",
+                "p10.jpg",
+                "p10a.jpg"
+            ]
+        );
+    }
+
+    #[test]
+    fn orders_equal_stems_by_extension() {
+        assert_eq!(natural_cmp("p1.jpg", "p1.jpg"), Ordering::Equal);
+        assert_eq!(natural_cmp("p1.jpg", "p1.png"), Ordering::Less);
+        assert_eq!(natural_cmp("p1", "p1.jpg"), Ordering::Less);
+    }
+}
@@ -0,0 +1,268 @@
+use image::{DynamicImage, GrayImage};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+// Ink trimmed at both ends of a profile, page numbers and running heads fall in it
+const PROFILE_TRIM: f64 = 0.02;
+// The line direction is only trusted when one profile is this much more striped than the other
+const MIN_STRIPE_RATIO: f64 = 1.3;
+// Positions with less ink than this ratio of the mean profile are the gap between two lines
+const LINE_GAP_RATIO: f64 = 0.05;
+// Thinnest band of ink taken as a line, in analysed pixels
+const MIN_LINE_WIDTH: usize = 3;
+const MIN_LINES: usize = 4;
+// Ink trimmed at both ends of a line, so a stray speck does not move them
+const LINE_TRIM: f64 = 0.01;
+// Lines are aligned at their start and ragged at their end (short last lines of
+// paragraphs), one end must be this many times more ragged than the other
+const MIN_RAGGED_RATIO: f64 = 2.0;
+// Raggedness in analysed pixels under which both ends count as aligned
+const MIN_RAGGEDNESS: f64 = 2.0;
+
+/// Direction text lines run in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layout {
+    // left to right lines stacked top to bottom
+    Horizontal,
+    // tategaki, top to bottom columns
+    Vertical,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Layout::Horizontal => "horizontal",
+            Layout::Vertical => "vertical",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "horizontal" => Ok(Layout::Horizontal),
+            "vertical" => Ok(Layout::Vertical),
+            _ => Err(format!("unknown text layout {}", s)),
+        }
+    }
+}
+
+/// The text lines found on a page
+#[derive(Clone, Copy, Debug)]
+pub struct Lines {
+    pub direction: Layout,
+    // lines are aligned on the top (vertical) or left (horizontal) side rather
+    // than the opposite one, `None` when both ends are as ragged
+    pub start_near: Option<bool>,
+}
+
+// Ink per row and per column
+fn profiles(mask: &GrayImage) -> (Vec<u32>, Vec<u32>) {
+    let mut rows = vec![0; mask.height() as usize];
+    let mut columns = vec![0; mask.width() as usize];
+    for (x, y, pixel) in mask.enumerate_pixels() {
+        if pixel[0] > 0 {
+            rows[y as usize] += 1;
+            columns[x as usize] += 1;
+        }
+    }
+    (rows, columns)
+}
+
+// The part of a profile holding all but `trim` of its ink at each end
+fn trimmed_span(profile: &[u32], trim: f64) -> Option<(usize, usize)> {
+    let total: u64 = profile.iter().map(|&ink| ink as u64).sum();
+    if total == 0 {
+        return None;
+    }
+    let cut = (total as f64 * trim) as u64;
+    let mut ink = 0;
+    let start = profile.iter().position(|&value| {
+        ink += value as u64;
+        ink > cut
+    })?;
+    let mut ink = 0;
+    let end = profile.len()
+        - profile.iter().rev().position(|&value| {
+            ink += value as u64;
+            ink > cut
+        })?;
+    Some((start, end))
+}
+
+// Coefficient of variation, high when ink alternates with blank gaps
+fn stripes(profile: &[u32]) -> f64 {
+    let n = profile.len() as f64;
+    let mean = profile.iter().map(|&ink| ink as f64).sum::<f64>() / n;
+    if mean == 0.0 {
+        return 0.0;
+    }
+    let variance = profile
+        .iter()
+        .map(|&ink| (ink as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    variance.sqrt() / mean
+}
+
+// Runs of positions with ink, at least `MIN_LINE_WIDTH` wide
+fn bands(profile: &[u32], offset: usize) -> Vec<(usize, usize)> {
+    let mean = profile.iter().map(|&ink| ink as f64).sum::<f64>() / profile.len() as f64;
+    let gap = mean * LINE_GAP_RATIO;
+    let mut bands = Vec::new();
+    let mut start = None;
+    for (i, &ink) in profile.iter().enumerate() {
+        match (ink as f64 > gap, start) {
+            (true, None) => start = Some(i),
+            (false, Some(band_start)) => {
+                bands.push((band_start + offset, i + offset));
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(band_start) = start {
+        bands.push((band_start + offset, profile.len() + offset));
+    }
+    bands.retain(|(start, end)| end - start >= MIN_LINE_WIDTH);
+    bands
+}
+
+// Start and end of the ink of one line along its direction
+fn line_extent(
+    mask: &GrayImage,
+    band: (usize, usize),
+    direction: Layout,
+) -> Option<(usize, usize)> {
+    let along = match direction {
+        Layout::Horizontal => mask.width(),
+        Layout::Vertical => mask.height(),
+    };
+    let mut profile = vec![0; along as usize];
+    for i in band.0..band.1 {
+        for (j, ink) in profile.iter_mut().enumerate() {
+            let (x, y) = match direction {
+                Layout::Horizontal => (j as u32, i as u32),
+                Layout::Vertical => (i as u32, j as u32),
+            };
+            if mask.get_pixel(x, y)[0] > 0 {
+                *ink += 1;
+            }
+        }
+    }
+    trimmed_span(&profile, LINE_TRIM)
+}
+
+// Mean distance of `positions` to the 10th percentile of them
+fn raggedness(positions: &mut [f64]) -> f64 {
+    positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let reference = positions[positions.len() / 10];
+    positions
+        .iter()
+        .map(|position| (position - reference).max(0.0))
+        .sum::<f64>()
+        / positions.len() as f64
+}
+
+/// Find the direction of the text lines of a page and the side they start on.
+///
+/// `mask` is the binarized page, ink being non zero. Lines show as stripes in
+/// the ink profile across them, their aligned side is the one with the least
+/// ragged ends. `None` when the page has no clear lines.
+pub fn detect(mask: &GrayImage) -> Option<Lines> {
+    let (rows, columns) = profiles(mask);
+    let (top, bottom) = trimmed_span(&rows, PROFILE_TRIM)?;
+    let (left, right) = trimmed_span(&columns, PROFILE_TRIM)?;
+    let row_stripes = stripes(&rows[top..bottom]);
+    let column_stripes = stripes(&columns[left..right]);
+    let direction = if row_stripes > column_stripes * MIN_STRIPE_RATIO {
+        Layout::Horizontal
+    } else if column_stripes > row_stripes * MIN_STRIPE_RATIO {
+        Layout::Vertical
+    } else {
+        return None;
+    };
+
+    let lines = match direction {
+        Layout::Horizontal => bands(&rows[top..bottom], top),
+        Layout::Vertical => bands(&columns[left..right], left),
+    };
+    let extents: Vec<(usize, usize)> = lines
+        .into_iter()
+        .filter_map(|band| line_extent(mask, band, direction))
+        .collect();
+    if extents.len() < MIN_LINES {
+        return None;
+    }
+
+    let mut starts: Vec<f64> = extents.iter().map(|&(start, _)| start as f64).collect();
+    // ends are mirrored so both are measured from their own side
+    let mut ends: Vec<f64> = extents.iter().map(|&(_, end)| -(end as f64)).collect();
+    let start_ragged = raggedness(&mut starts).max(MIN_RAGGEDNESS);
+    let end_ragged = raggedness(&mut ends).max(MIN_RAGGEDNESS);
+    let start_near = if end_ragged > start_ragged * MIN_RAGGED_RATIO {
+        Some(true)
+    } else if start_ragged > end_ragged * MIN_RAGGED_RATIO {
+        Some(false)
+    } else {
+        None
+    };
+    Some(Lines {
+        direction,
+        start_near,
+    })
+}
+
+/// The layout most pages with lines have
+pub fn dominant_layout<'a, I: IntoIterator<Item = &'a Lines>>(lines: I) -> Option<Layout> {
+    let (mut horizontal, mut vertical) = (0, 0);
+    for page_lines in lines {
+        match page_lines.direction {
+            Layout::Horizontal => horizontal += 1,
+            Layout::Vertical => vertical += 1,
+        }
+    }
+    match horizontal.cmp(&vertical) {
+        Ordering::Greater => Some(Layout::Horizontal),
+        Ordering::Less => Some(Layout::Vertical),
+        Ordering::Equal => None,
+    }
+}
+
+/// Clockwise rotation in degrees that turns a page of a `layout` book upright.
+///
+/// Lines across the layout mean a sideways page, turned one way or the other
+/// depending on the side they start on. `None` when that side is unclear.
+/// Upright lines that start on the wrong side are an upside down page.
+pub fn rotation(lines: &Lines, layout: Layout) -> Option<u32> {
+    if lines.direction == layout {
+        // an unclear start is taken as upright
+        return Some(if lines.start_near == Some(false) {
+            180
+        } else {
+            0
+        });
+    }
+    let start_near = lines.start_near?;
+    let turned_clockwise = match layout {
+        // columns turned clockwise become lines starting on the right
+        Layout::Vertical => !start_near,
+        // lines turned clockwise become columns starting at the top
+        Layout::Horizontal => start_near,
+    };
+    Some(if turned_clockwise { 270 } else { 90 })
+}
+
+/// Rotate a page clockwise by a multiple of 90 degrees
+pub fn rotate(image: &DynamicImage, degrees: u32) -> DynamicImage {
+    match degrees % 360 {
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        _ => image.clone(),
+    }
+}
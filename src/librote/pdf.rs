@@ -1,102 +1,724 @@
-use filesize::PathExt;
-use genpdf::{elements, fonts};
-use glob::glob;
-use log::{debug, info};
-use std::convert::From;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, ImageFormat};
+use log::{debug, info, warn};
+use lopdf::content::{Content, Operation};
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, Stream};
+use std::collections::VecDeque;
+use std::convert::TryInto;
 use std::fs;
-use std::process::Command;
+use std::str::FromStr;
 
+use crate::librote::archive;
+use crate::librote::chunks::{Chunk, ChunkManifest, ChunkPage};
 use crate::librote::error;
-use crate::librote::OcrPlan;
+use crate::librote::marker;
+use crate::librote::progress;
+use crate::librote::{OcrPlan, OCR_PLAN_PATH};
 
 // Google drive OCR for PDF file has a 2 MB hard limit
 // However, through testing, we can actually use this number instead
 const GOOGLE_DRIVE_OCR_LIMIT: u64 = 2_800_000;
+// Bytes a page adds besides its image: page and content objects, xref entries
+const PAGE_OVERHEAD: u64 = 512;
+// Bytes of a chunk besides its pages: header, catalog, page tree and trailer
+const CHUNK_OVERHEAD: u64 = 1024;
 
-const FONT_DIRS: &[&str] = &[
-    "/usr/share/fonts/liberation",
-    "/usr/share/fonts/truetype/liberation",
-];
-
-const DEFAULT_FONT_NAME: &'static str = "LiberationSans";
-
-pub fn gen_pdf(input: &str) -> Result<u8, error::Error> {
-    let ocr_plan: OcrPlan =
-        toml::from_str(&fs::read_to_string("ocr_plan.toml").expect("could not read ocr_plan.toml"))
-            .expect("Could not read OCR plan");
-    let mut current_chunk: u8 = 1;
-    let mut current_size = 0;
-
-    let mut current_pdf_vec: Vec<String> = Vec::new();
-
-    for i in glob(&format!("{}/*", input)).expect("Failed to read glob pattern") {
-        match i {
-            Ok(path) => {
-                if ocr_plan.ignore(String::from(path.to_str().unwrap())) {
-                    continue;
-                } else {
-                    let current_file_size = path.size_on_disk().expect("Could not read file size");
-                    if current_size + current_file_size > GOOGLE_DRIVE_OCR_LIMIT {
-                        write_pdf(current_pdf_vec, current_chunk)?;
-                        current_pdf_vec = Vec::new();
-                        current_chunk += 1;
-                        current_size = 0;
+// Recompression steps for a page too large for a chunk, tried in order
+const JPEG_QUALITIES: [u8; 3] = [85, 70, 55];
+// Each downscaling step keeps this part of the width and height
+const DOWNSCALE_FACTOR: f64 = 0.8;
+// Pages are not downscaled below this shorter side, the text would be unreadable
+const MIN_SIDE: u32 = 600;
+
+// Resolution assumed for pages that do not give a plausible one
+const DEFAULT_DPI: f64 = 300.0;
+// Resolutions a page may declare, 72 or 96 is rather the default of an editor
+const MIN_DPI: f64 = 100.0;
+const MAX_DPI: f64 = 1200.0;
+const POINTS_PER_INCH: f64 = 72.0;
+const POINTS_PER_MM: f64 = POINTS_PER_INCH / 25.4;
+
+const PAGE_MARGIN: f64 = 10.0;
+// Space between the marker and the page image below it
+const MARKER_GAP: f64 = 12.0;
+
+/// Fixed paper size of the PDF pages, in points
+#[derive(Clone, Copy, Debug)]
+pub struct Paper {
+    width: f64,
+    height: f64,
+}
+
+impl FromStr for Paper {
+    type Err = String;
+
+    /// A paper name or `WIDTHxHEIGHT` in millimetres
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = match s.to_lowercase().as_str() {
+            "a4" => (210.0, 297.0),
+            "a5" => (148.0, 210.0),
+            // bunkobon
+            "a6" => (105.0, 148.0),
+            // JIS sizes of tankobon and shinsho
+            "b5" => (182.0, 257.0),
+            "b6" => (128.0, 182.0),
+            "letter" => (215.9, 279.4),
+            size => {
+                let (width, height) = size
+                    .split_once('x')
+                    .ok_or_else(|| format!("unknown paper size {}", s))?;
+                let parse = |side: &str| {
+                    side.trim()
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|side| *side > 0.0)
+                        .ok_or_else(|| format!("invalid paper size {}", s))
+                };
+                (parse(width)?, parse(height)?)
+            }
+        };
+        Ok(Paper {
+            width: width * POINTS_PER_MM,
+            height: height * POINTS_PER_MM,
+        })
+    }
+}
+
+/// A resolution given on the command line, within the ones a page may declare
+pub fn parse_dpi(s: &str) -> Result<f64, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|dpi| (MIN_DPI..=MAX_DPI).contains(dpi))
+        .ok_or_else(|| format!("{} is not a resolution from {} to {}", s, MIN_DPI, MAX_DPI))
+}
+
+pub struct PdfOptions {
+    // every page on this paper, sized from its image if `None`
+    pub paper: Option<Paper>,
+    // resolution of every page, read from each image if `None`
+    pub dpi: Option<f64>,
+    pub jobs: usize,
+}
+
+// A page image and its marker ready to be embedded, with what was done to make it fit
+struct EncodedPage {
+    // number in the marker
+    number: u32,
+    // page path in the plan
+    page: String,
+    // file the image was read from
+    path: String,
+    stream: Stream,
+    marker: Stream,
+    // size of the original image at its resolution, in points
+    points: (f64, f64),
+    original_size: u64,
+    md5: String,
+    actions: Vec<String>,
+}
+
+impl EncodedPage {
+    fn size(&self) -> u64 {
+        (self.stream.content.len() + self.marker.content.len()) as u64 + PAGE_OVERHEAD
+    }
+}
+
+/// Write the chunk PDFs of the text pages, returns the manifest of the chunks.
+///
+/// Pages are added to a chunk while its encoded size stays under the Drive
+/// OCR limit. A page too large on its own is recompressed, turned grayscale
+/// and downscaled until it fits, and every chunk is checked before writing.
+pub fn gen_pdf(input: &str, options: &PdfOptions) -> Result<ChunkManifest, error::Error> {
+    let ocr_plan = OcrPlan::load(OCR_PLAN_PATH);
+    // (marker number, page, image path), markers count the text pages of the book
+    let text_pages: Vec<(u32, String, String)> = ocr_plan
+        .pages(input)
+        .into_iter()
+        .filter(|page| !ocr_plan.ignore(page.clone()))
+        .enumerate()
+        .map(|(i, page)| {
+            let image_path = ocr_plan.image_path(&page);
+            (i as u32 + 1, page, image_path)
+        })
+        .collect();
+
+    let budget = GOOGLE_DRIVE_OCR_LIMIT - CHUNK_OVERHEAD;
+    let encoded = progress::parallel_map(
+        "Encoding",
+        &text_pages,
+        options.jobs,
+        |(number, page, path)| encode_page(*number, page, path, budget, options.dpi),
+    );
+    let mut pages = VecDeque::new();
+    for page in encoded {
+        let page = page?;
+        if !page.actions.is_empty() {
+            info!(
+                "`{}` shrunk from {} to {} bytes: {}",
+                page.path,
+                page.original_size,
+                page.stream.content.len(),
+                page.actions.join(", ")
+            );
+        }
+        pages.push_back(page);
+    }
+
+    let mut chunks = Vec::new();
+    let mut current_chunk: u32 = 0;
+    while !pages.is_empty() {
+        current_chunk += 1;
+        let mut count = 0;
+        let mut current_size = 0;
+        for page in &pages {
+            if count > 0 && current_size + page.size() > budget {
+                break;
+            }
+            debug!(
+                "Added `{}` size `{}` to pdf_chunk {}",
+                page.path,
+                page.size(),
+                current_chunk
+            );
+            current_size += page.size();
+            count += 1;
+        }
+        // the estimate should hold, if not the chunk loses its last pages
+        let pdf = loop {
+            let pdf = build_pdf(pages.range(..count), options.paper)?;
+            if pdf.len() as u64 <= GOOGLE_DRIVE_OCR_LIMIT {
+                break pdf;
+            }
+            if count == 1 {
+                warn!(
+                    "Chunk {} is {} bytes, over the Drive OCR limit of {}",
+                    current_chunk,
+                    pdf.len(),
+                    GOOGLE_DRIVE_OCR_LIMIT
+                );
+                break pdf;
+            }
+            debug!(
+                "Chunk {} is {} bytes, moving `{}` to the next one",
+                current_chunk,
+                pdf.len(),
+                pages[count - 1].path
+            );
+            count -= 1;
+        };
+
+        let pdf_path = format!("chunk_{:02}.pdf", current_chunk);
+        fs::write(&pdf_path, &pdf)?;
+        info!(
+            "Finished writing pdf file for chunk {}, {} bytes",
+            current_chunk,
+            pdf.len()
+        );
+        chunks.push(Chunk {
+            index: current_chunk,
+            pdf_path,
+            size: pdf.len() as u64,
+            md5: format!("{:x}", md5::compute(&pdf)),
+            pages: pages
+                .drain(..count)
+                .map(|page| ChunkPage {
+                    marker: page.number,
+                    size: page.stream.content.len() as u64,
+                    path: page.page,
+                    image_path: page.path,
+                    md5: page.md5,
+                    shrunk: page.actions,
+                })
+                .collect(),
+        });
+    }
+    Ok(ChunkManifest::new(chunks))
+}
+
+// Encode a page, shrinking it until it takes at most `budget` bytes
+fn encode_page(
+    number: u32,
+    page: &str,
+    path: &str,
+    budget: u64,
+    dpi: Option<f64>,
+) -> Result<EncodedPage, error::Error> {
+    // pages may come from an archive
+    let data = archive::read(path)?;
+    let stream = image_stream(&data)?;
+    let (width, height) = dimensions(&stream)?;
+    // taken before any downscaling, so a smaller page keeps its physical size
+    let dpi = dpi.or_else(|| resolution(&data)).unwrap_or(DEFAULT_DPI);
+    let points = (
+        width as f64 * POINTS_PER_INCH / dpi,
+        height as f64 * POINTS_PER_INCH / dpi,
+    );
+    let mut page = EncodedPage {
+        number,
+        page: String::from(page),
+        path: String::from(path),
+        stream,
+        marker: raw_stream(&DynamicImage::ImageLuma8(marker::render(number)))?,
+        points,
+        original_size: data.len() as u64,
+        md5: format!("{:x}", md5::compute(&data)),
+        actions: Vec::new(),
+    };
+    if page.size() <= budget {
+        return Ok(page);
+    }
+
+    let mut image = image::load_from_memory(&data)?;
+    let binary = is_binary(&image);
+    let color = image.color().has_color();
+    if !binary {
+        // recompress at lower qualities, in colour first if the page has any
+        let mut versions = Vec::new();
+        if color {
+            versions.push((image.clone(), false));
+        }
+        versions.push((DynamicImage::ImageLuma8(image.to_luma8()), true));
+        for (version, grayscale) in versions {
+            for &quality in &JPEG_QUALITIES {
+                page.stream = jpeg_stream(&version, quality)?;
+                if page.size() <= budget {
+                    if grayscale && color {
+                        page.actions.push(String::from("converted to grayscale"));
                     }
-                    current_pdf_vec.push(String::from(path.to_str().unwrap()));
-                    debug!(
-                        "Added `{}` size `{}` to pdf_chunk {}",
-                        path.display(),
-                        current_file_size,
-                        current_chunk
-                    );
-                    current_size += current_file_size;
+                    page.actions
+                        .push(format!("recompressed as JPEG quality {}", quality));
+                    return Ok(page);
                 }
             }
-            Err(_e) => (),
-        }
-    }
-
-    if !current_pdf_vec.is_empty() {
-        write_pdf(current_pdf_vec, current_chunk)?;
-    }
-    Ok(current_chunk)
-}
-
-fn write_pdf(image_vec: Vec<String>, chunk_number: u8) -> Result<(), error::Error> {
-    // for actual physical book scan A6 is good enough
-    // However, high quality digital download from services such as BookWalker
-    // can result in files that have resolution bigger than 1748x1240
-    let a5_paper_size = genpdf::Size::new(148, 210);
-
-    let font_dir = FONT_DIRS
-        .iter()
-        .filter(|path| std::path::Path::new(path).exists())
-        .next()
-        .expect("Could not find font directory");
-    let default_font =
-        fonts::from_files(font_dir, DEFAULT_FONT_NAME, Some(fonts::Builtin::Helvetica))
-            .expect("Failed to load the default font family");
-    let mut doc = genpdf::Document::new(default_font);
-    doc.set_minimal_conformance();
-    doc.set_paper_size(a5_paper_size);
-    for path in image_vec {
-        doc.push(elements::Image::from_path(path).expect("could not push image to pdf file"));
-        doc.push(elements::PageBreak::new());
-        doc.push(
-            elements::Image::from_path("marker.png").expect("could not push image to pdf file"),
+        }
+        image = DynamicImage::ImageLuma8(image.to_luma8());
+        if color {
+            page.actions.push(String::from("converted to grayscale"));
+        }
+    }
+
+    // then smaller and smaller, at the lowest quality
+    let lowest_quality = JPEG_QUALITIES[JPEG_QUALITIES.len() - 1];
+    loop {
+        let width = (image.width() as f64 * DOWNSCALE_FACTOR) as u32;
+        let height = (image.height() as f64 * DOWNSCALE_FACTOR) as u32;
+        if width.min(height) < MIN_SIDE {
+            warn!(
+                "`{}` is still {} bytes at {}x{}, over the chunk budget",
+                path,
+                page.stream.content.len(),
+                image.width(),
+                image.height()
+            );
+            break;
+        }
+        image = image.resize_exact(width, height, FilterType::Triangle);
+        page.stream = if binary {
+            // resampling makes grey edges, they are thresholded back
+            let mut luma = image.to_luma8();
+            for pixel in luma.pixels_mut() {
+                pixel[0] = if pixel[0] < 128 { 0 } else { 255 };
+            }
+            image = DynamicImage::ImageLuma8(luma);
+            raw_stream(&image)?
+        } else {
+            jpeg_stream(&image, lowest_quality)?
+        };
+        if page.size() <= budget {
+            break;
+        }
+    }
+    page.actions.push(format!(
+        "downscaled to {}x{}",
+        image.width(),
+        image.height()
+    ));
+    if !binary {
+        page.actions
+            .push(format!("recompressed as JPEG quality {}", lowest_quality));
+    }
+    Ok(page)
+}
+
+/// Declared resolution of a JPEG (JFIF) or PNG (pHYs) in dots per inch, if plausible
+pub fn resolution(data: &[u8]) -> Option<f64> {
+    let dpi = match image::guess_format(data).ok()? {
+        ImageFormat::Jpeg => {
+            // the JFIF segment comes right after the start of image
+            let jfif = data.get(4..18)?;
+            if &jfif[2..7] != b"JFIF\0" {
+                return None;
+            }
+            let density = u16::from_be_bytes([jfif[10], jfif[11]]) as f64;
+            match jfif[9] {
+                1 => density,
+                2 => density * 2.54,
+                _ => return None,
+            }
+        }
+        ImageFormat::Png => {
+            let mut i = 8;
+            loop {
+                let length = u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?) as usize;
+                let kind = data.get(i + 4..i + 8)?;
+                if kind == b"pHYs" {
+                    let phys = data.get(i + 8..i + 17)?;
+                    if phys[8] != 1 {
+                        return None;
+                    }
+                    // pixels per metre
+                    break u32::from_be_bytes(phys[0..4].try_into().ok()?) as f64 * 0.0254;
+                }
+                if kind == b"IDAT" {
+                    return None;
+                }
+                i += 12 + length;
+            }
+        }
+        _ => return None,
+    };
+    Some(dpi).filter(|dpi| (MIN_DPI..=MAX_DPI).contains(dpi))
+}
+
+// Width, height and number of components of a baseline or progressive JPEG
+fn jpeg_frame(data: &[u8]) -> Option<(u32, u32, u8)> {
+    let mut i = 2;
+    while i + 4 <= data.len() {
+        if data[i] != 0xFF {
+            return None;
+        }
+        let marker = data[i + 1];
+        let length = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        // start of frame, except the DHT, JPG and DAC markers sharing the range
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            let frame = data.get(i + 4..i + 10)?;
+            if frame[0] != 8 {
+                return None;
+            }
+            let height = u16::from_be_bytes([frame[1], frame[2]]) as u32;
+            let width = u16::from_be_bytes([frame[3], frame[4]]) as u32;
+            return Some((width, height, frame[5]));
+        }
+        i += 2 + length;
+    }
+    None
+}
+
+/// An image XObject of a page.
+///
+/// Grayscale and RGB JPEGs are embedded as they are (DCT), anything else is
+/// decoded and its samples stored with Flate. Black and white pages, such as
+/// binarized ones, take one bit per pixel.
+fn image_stream(data: &[u8]) -> Result<Stream, error::Error> {
+    if image::guess_format(data).ok() == Some(ImageFormat::Jpeg) {
+        if let Some((width, height, components @ (1 | 3))) = jpeg_frame(data) {
+            let dict = dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => width,
+                "Height" => height,
+                "ColorSpace" => if components == 1 { "DeviceGray" } else { "DeviceRGB" },
+                "BitsPerComponent" => 8,
+                "Filter" => "DCTDecode",
+            };
+            return Ok(Stream::new(dict, data.to_vec()).with_compression(false));
+        }
+    }
+
+    raw_stream(&image::load_from_memory(data)?)
+}
+
+// Black and white only, such as a binarized page
+fn is_binary(image: &DynamicImage) -> bool {
+    !image.color().has_color() && image.to_luma8().pixels().all(|p| p[0] == 0 || p[0] == 255)
+}
+
+// The samples of an image with Flate, one bit per pixel when it is black and white
+fn raw_stream(image: &DynamicImage) -> Result<Stream, error::Error> {
+    let (width, height) = (image.width(), image.height());
+    let (color_space, bits, samples) = if image.color().has_color() {
+        ("DeviceRGB", 8, image.to_rgb8().into_raw())
+    } else if is_binary(image) {
+        ("DeviceGray", 1, pack_bits(&image.to_luma8()))
+    } else {
+        ("DeviceGray", 8, image.to_luma8().into_raw())
+    };
+    let dict = dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Image",
+        "Width" => width,
+        "Height" => height,
+        "ColorSpace" => color_space,
+        "BitsPerComponent" => bits,
+    };
+    let mut stream = Stream::new(dict, samples);
+    stream.compress()?;
+    Ok(stream)
+}
+
+fn jpeg_stream(image: &DynamicImage, quality: u8) -> Result<Stream, error::Error> {
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality).encode_image(image)?;
+    image_stream(&jpeg)
+}
+
+// One bit per pixel, rows padded to a whole byte, white being 1
+fn pack_bits(luma: &GrayImage) -> Vec<u8> {
+    let row_bytes = luma.width().div_ceil(8) as usize;
+    let mut packed = vec![0u8; row_bytes * luma.height() as usize];
+    for (x, y, pixel) in luma.enumerate_pixels() {
+        if pixel[0] == 255 {
+            packed[y as usize * row_bytes + x as usize / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    packed
+}
+
+fn dimensions(stream: &Stream) -> Result<(u32, u32), error::Error> {
+    let width = stream.dict.get(b"Width")?.as_i64()?;
+    let height = stream.dict.get(b"Height")?.as_i64()?;
+    Ok((width as u32, height as u32))
+}
+
+// Draw an image XObject of `width` x `height` points with its top left corner at (x, top)
+fn draw_image(operations: &mut Vec<Operation>, name: &str, x: f64, top: f64, size: (f64, f64)) {
+    let (width, height) = size;
+    operations.push(Operation::new("q", vec![]));
+    operations.push(Operation::new(
+        "cm",
+        vec![
+            width.into(),
+            0.into(),
+            0.into(),
+            height.into(),
+            x.into(),
+            (top - height).into(),
+        ],
+    ));
+    operations.push(Operation::new("Do", vec![Object::Name(name.into())]));
+    operations.push(Operation::new("Q", vec![]));
+}
+
+/// A chunk PDF with one page image per PDF page.
+///
+/// Every page is followed by its marker, at the top of the next PDF page or
+/// alone on a last one, so the OCR text of a page ends with its marker.
+///
+/// A PDF page is as large as its image at the image resolution, so the OCR
+/// gets every pixel, unless a `paper` size is given to fit the images in.
+fn build_pdf<'a, I: IntoIterator<Item = &'a EncodedPage>>(
+    pages: I,
+    paper: Option<Paper>,
+) -> Result<Vec<u8>, error::Error> {
+    let pages: Vec<&EncodedPage> = pages.into_iter().collect();
+    let mut doc = Document::with_version("1.5");
+    let pages_id = doc.new_object_id();
+
+    let mut page_ids: Vec<ObjectId> = Vec::new();
+    for index in 0..=pages.len() {
+        let marker = index.checked_sub(1).map(|previous| &pages[previous].marker);
+        let image = pages.get(index);
+        // the marker spans the page width
+        let marker_height = |box_width: f64| -> Result<f64, error::Error> {
+            let (marker_width, marker_height) = match marker {
+                Some(marker) => dimensions(marker)?,
+                None => return Ok(0.0),
+            };
+            Ok(marker_height as f64 * box_width / marker_width as f64 + MARKER_GAP)
+        };
+        let (page_width, page_height) = match paper {
+            Some(paper) => (paper.width, paper.height),
+            None => {
+                // the marker only page is as wide as the page before it
+                let (width, height) = match image {
+                    Some(page) => page.points,
+                    None => (pages[index - 1].points.0, 0.0),
+                };
+                (
+                    width + 2.0 * PAGE_MARGIN,
+                    height + marker_height(width)? + 2.0 * PAGE_MARGIN,
+                )
+            }
+        };
+        let box_width = page_width - 2.0 * PAGE_MARGIN;
+
+        let mut operations = Vec::new();
+        let mut xobjects = Dictionary::new();
+        let mut top = page_height - PAGE_MARGIN;
+        if let Some(marker) = marker {
+            let marker_height = marker_height(box_width)? - MARKER_GAP;
+            draw_image(
+                &mut operations,
+                "Marker",
+                PAGE_MARGIN,
+                top,
+                (box_width, marker_height),
+            );
+            xobjects.set("Marker", doc.add_object(marker.clone()));
+            top -= marker_height + MARKER_GAP;
+        }
+        if let Some(page) = image {
+            let (width, height) = dimensions(&page.stream)?;
+            // fitted in the space left, keeping its aspect ratio
+            let scale = (box_width / width as f64).min((top - PAGE_MARGIN) / height as f64);
+            let size = (width as f64 * scale, height as f64 * scale);
+            // centred horizontally, at the top of the space left
+            let x = PAGE_MARGIN + (box_width - size.0) / 2.0;
+            draw_image(&mut operations, "Page", x, top, size);
+            xobjects.set("Page", doc.add_object(page.stream.clone()));
+        }
+
+        let content = Content { operations };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode()?));
+        page_ids.push(doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+            "Resources" => dictionary! { "XObject" => xobjects },
+            "MediaBox" => vec![0.into(), 0.into(), page_width.into(), page_height.into()],
+        }));
+    }
+
+    let pages = dictionary! {
+        "Type" => "Pages",
+        "Count" => page_ids.len() as i64,
+        "Kids" => page_ids.into_iter().map(Object::from).collect::<Vec<_>>(),
+    };
+    doc.objects.insert(pages_id, Object::Dictionary(pages));
+    let catalog_id = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    doc.trailer.set("Root", catalog_id);
+    let mut pdf = Vec::new();
+    doc.save_to(&mut pdf)?;
+    Ok(pdf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, RgbImage};
+
+    // a segment of a JPEG: marker, length including itself, payload
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    // precision, height, width and number of components of a frame header
+    fn frame(width: u16, height: u16, components: u8) -> Vec<u8> {
+        let mut frame = vec![8];
+        frame.extend_from_slice(&height.to_be_bytes());
+        frame.extend_from_slice(&width.to_be_bytes());
+        frame.push(components);
+        frame
+    }
+
+    #[test]
+    fn reads_the_frame_of_a_baseline_jpeg() {
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg)
+            .encode_image(&DynamicImage::ImageRgb8(RgbImage::new(37, 21)))
+            .unwrap();
+        assert_eq!(jpeg_frame(&jpeg), Some((37, 21, 3)));
+    }
+
+    #[test]
+    fn reads_the_frame_of_a_progressive_jpeg() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(segment(0xE0, b"JFIF\0\x01\x01\x00\x00\x01\x00\x01\x00\x00"));
+        jpeg.extend(segment(0xC2, &frame(640, 480, 1)));
+        assert_eq!(jpeg_frame(&jpeg), Some((640, 480, 1)));
+    }
+
+    #[test]
+    fn skips_the_markers_sharing_the_frame_range() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        // a DHT and a DAC before the frame, with bytes that would read as one
+        jpeg.extend(segment(0xC4, &frame(1, 1, 3)));
+        jpeg.extend(segment(0xCC, &frame(2, 2, 3)));
+        jpeg.extend(segment(0xC0, &frame(300, 200, 3)));
+        assert_eq!(jpeg_frame(&jpeg), Some((300, 200, 3)));
+    }
+
+    #[test]
+    fn pads_packed_rows_to_a_byte() {
+        // 10 pixels wide: white, black, then white, on two rows
+        let luma = GrayImage::from_fn(10, 2, |x, y| Luma([if (x + y) % 2 == 0 { 255 } else { 0 }]));
+        assert_eq!(
+            pack_bits(&luma),
+            vec![0b1010_1010, 0b1000_0000, 0b0101_0101, 0b0100_0000]
         );
-        doc.push(elements::Break::new(5))
-    }
-    doc.render_to_file(format!("tmp_{:02}.pdf", chunk_number))
-        .expect("Could not write to pdf file");
-    // pass the output pdf to `ps2pdf` to significantly reduce size due to a known issue of genpdf
-    Command::new("ps2pdf")
-        .arg(format!("tmp_{:02}.pdf", chunk_number))
-        .arg(format!("chunk_{:02}.pdf", chunk_number))
-        .status()
-        .expect("Could not spawn `ps2pdf`");
-    fs::remove_file(format!("tmp_{:02}.pdf", chunk_number))
-        .expect("could not remove the pdf from `genpdf`");
-    info!("Finished writing pdf file for chunk {}", chunk_number);
-    Ok(())
+    }
+
+    fn encoded_page(number: u32, width: u32, height: u32) -> EncodedPage {
+        let image = DynamicImage::ImageLuma8(GrayImage::from_pixel(width, height, Luma([128])));
+        EncodedPage {
+            number,
+            page: format!("p{}.png", number),
+            path: format!("p{}.png", number),
+            stream: raw_stream(&image).unwrap(),
+            marker: raw_stream(&DynamicImage::ImageLuma8(marker::render(number))).unwrap(),
+            points: (
+                width as f64 * POINTS_PER_INCH / DEFAULT_DPI,
+                height as f64 * POINTS_PER_INCH / DEFAULT_DPI,
+            ),
+            original_size: 0,
+            md5: String::new(),
+            actions: Vec::new(),
+        }
+    }
+
+    fn media_boxes(pdf: &[u8]) -> Vec<(f64, f64)> {
+        let document = Document::load_mem(pdf).unwrap();
+        document
+            .get_pages()
+            .values()
+            .map(|&page_id| {
+                let page = document.get_dictionary(page_id).unwrap();
+                let media_box = page.get(b"MediaBox").unwrap().as_array().unwrap();
+                let side = |i: usize| match &media_box[i] {
+                    Object::Integer(value) => *value as f64,
+                    Object::Real(value) => *value as f64,
+                    other => panic!("MediaBox holds {:?}", other),
+                };
+                (side(2), side(3))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn builds_a_page_per_image_and_one_for_the_last_marker() {
+        let pages = vec![encoded_page(1, 600, 900), encoded_page(2, 300, 450)];
+        let boxes = media_boxes(&build_pdf(&pages, None).unwrap());
+        assert_eq!(boxes.len(), 3);
+        // 600 pixels at 300 dpi are 144 points, within the margins
+        assert!((boxes[0].0 - (144.0 + 2.0 * PAGE_MARGIN)).abs() < 0.01);
+        assert!((boxes[1].0 - (72.0 + 2.0 * PAGE_MARGIN)).abs() < 0.01);
+        // the page following an image holds its marker above its own image
+        assert!(boxes[1].1 > 108.0 + 2.0 * PAGE_MARGIN);
+        assert!((boxes[2].0 - boxes[1].0).abs() < 0.01);
+    }
+
+    #[test]
+    fn puts_every_page_on_the_paper() {
+        let pages = vec![encoded_page(1, 600, 900), encoded_page(2, 900, 600)];
+        let paper: Paper = "a5".parse().unwrap();
+        for (width, height) in media_boxes(&build_pdf(&pages, Some(paper)).unwrap()) {
+            assert!((width - paper.width).abs() < 0.01);
+            assert!((height - paper.height).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn accepts_plausible_resolutions_only() {
+        assert_eq!(parse_dpi("600"), Ok(600.0));
+        assert!(parse_dpi("72").is_err());
+        assert!(parse_dpi("2400").is_err());
+        assert!(parse_dpi("high").is_err());
+    }
 }
@@ -0,0 +1,109 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+use std::fmt;
+
+// The page is reduced to this size before the DCT
+const HASH_IMAGE_SIZE: usize = 64;
+// Side of the low frequency block kept from the DCT, one bit per coefficient
+const HASH_SIZE: usize = 16;
+const HASH_WORDS: usize = HASH_SIZE * HASH_SIZE / 64;
+
+/// A 256 bit perceptual hash, written to the plan as hexadecimal
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct PHash([u64; HASH_WORDS]);
+
+impl PHash {
+    /// Number of differing bits, 0 for identical pages
+    pub fn distance(&self, other: &PHash) -> u32 {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum()
+    }
+}
+
+impl fmt::Display for PHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for word in &self.0 {
+            write!(f, "{:016x}", word)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<PHash> for String {
+    fn from(hash: PHash) -> Self {
+        hash.to_string()
+    }
+}
+
+impl TryFrom<String> for PHash {
+    type Error = String;
+
+    fn try_from(hex: String) -> Result<Self, Self::Error> {
+        if hex.len() != HASH_WORDS * 16 {
+            return Err(format!("invalid perceptual hash {}", hex));
+        }
+        let mut words = [0u64; HASH_WORDS];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = u64::from_str_radix(&hex[i * 16..(i + 1) * 16], 16)
+                .map_err(|_| format!("invalid perceptual hash {}", hex))?;
+        }
+        Ok(PHash(words))
+    }
+}
+
+// One dimensional DCT-II of `input`, only the first `HASH_SIZE` coefficients
+fn dct(input: &[f64]) -> Vec<f64> {
+    let n = input.len() as f64;
+    (0..HASH_SIZE)
+        .map(|k| {
+            input
+                .iter()
+                .enumerate()
+                .map(|(i, value)| value * (PI * (i as f64 + 0.5) * k as f64 / n).cos())
+                .sum()
+        })
+        .collect()
+}
+
+/// Perceptual hash of a page.
+///
+/// The page is reduced to 64x64 grey, each low frequency DCT coefficient gives
+/// one bit: set when it is above the median. Rescans of the same page stay a
+/// few bits apart, while different pages differ in about half of the bits.
+pub fn phash(image: &DynamicImage) -> PHash {
+    let size = HASH_IMAGE_SIZE as u32;
+    let luma = image
+        .resize_exact(size, size, FilterType::Triangle)
+        .to_luma8();
+
+    // separable 2D DCT: rows first, then the columns of the result
+    let rows: Vec<Vec<f64>> = luma
+        .rows()
+        .map(|row| dct(&row.map(|p| p[0] as f64).collect::<Vec<f64>>()))
+        .collect();
+    let mut coefficients = Vec::with_capacity(HASH_SIZE * HASH_SIZE);
+    for u in 0..HASH_SIZE {
+        let column: Vec<f64> = rows.iter().map(|row| row[u]).collect();
+        coefficients.extend(dct(&column));
+    }
+
+    // the DC coefficient is the mean brightness, it would dominate the median
+    let mut sorted: Vec<f64> = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = sorted[sorted.len() / 2];
+
+    let mut words = [0u64; HASH_WORDS];
+    for (i, coefficient) in coefficients.iter().enumerate() {
+        if *coefficient > median {
+            words[i / 64] |= 1 << (i % 64);
+        }
+    }
+    PHash(words)
+}
@@ -1,51 +1,506 @@
-use glob::glob;
-use log::{debug, info, trace};
+use image::codecs::jpeg::JpegDecoder;
+use image::{DynamicImage, GrayImage, ImageFormat, Luma};
+use imageproc::filter::box_filter;
+use imageproc::region_labelling::{connected_components, Connectivity};
+use log::{debug, info, trace, warn};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
 
+use crate::librote::archive;
+use crate::librote::calibrate;
 use crate::librote::error;
-use crate::librote::OcrPlan;
+use crate::librote::model::Classifier;
+use crate::librote::orientation::{self, Layout, Lines};
+use crate::librote::pdf;
+use crate::librote::phash;
+use crate::librote::progress;
+use crate::librote::report::{self, Thumbnails};
+use crate::librote::spread::{self, SpreadNames};
+use crate::librote::{OcrPlan, PageClass, PageRecord, PageStats, Spread, Thresholds};
 
-enum PagePropertise {
-    Image,
-    TextPage,
-    EmptyPage,
+// Chroma (max - min of the RGB channels) above which a pixel counts as coloured.
+// Yellowed paper and scanner noise stay well below this
+const COLOR_CHROMA_THRESHOLD: u8 = 48;
+// Percentage of coloured pixels above which a page is a colour illustration
+pub const DEFAULT_COLOR_THRESHOLD: f64 = 5.0;
+
+// A pixel darker than the mean of its neighbourhood by this much is ink.
+// Paper grain, yellowing and faint show-through stay under it
+const INK_LOCAL_CONTRAST: i32 = 40;
+// Radius of the neighbourhood, in pixels of the analysed page
+const INK_BLOCK_RADIUS: u32 = 15;
+// Ink components smaller than this (in analysed pixels) are specks and scanner dust
+const MIN_INK_COMPONENT: usize = 8;
+// Percentage of ink at or below which a page is blank, a lone page number stays below
+pub const DEFAULT_EMPTY_INK_RATIO: f64 = 0.05;
+
+// Hamming distance (out of 256 bits) under which two pages are the same page.
+// Adjacent pages are usually a double feed, so a looser distance is allowed
+const DUPLICATE_DISTANCE: u32 = 16;
+const ADJACENT_DUPLICATE_DISTANCE: u32 = 32;
+// Rescans and double feeds sit close to their page, only this many earlier pages are compared.
+// Further apart, nearly blank pages (chapter ends, section breaks) would match each other
+const DUPLICATE_WINDOW: usize = 10;
+
+// Pages are analysed at most at this size, so memory stays bounded whatever the scan dpi
+const ANALYSIS_MAX_DIMENSION: u32 = 1600;
+// Pages decoded at full resolution at once (all but JPEG, and spreads), a 600 dpi
+// colour spread needs about 400 MB
+const FULL_DECODE_MAX_JOBS: usize = 4;
+
+static FULL_DECODES: Mutex<usize> = Mutex::new(0);
+static FULL_DECODE_DONE: Condvar = Condvar::new();
+
+// Run `f`, which decodes a page at full resolution, once fewer than
+// `FULL_DECODE_MAX_JOBS` other pages are
+fn full_decode<T>(f: impl FnOnce() -> T) -> T {
+    let mut running = FULL_DECODE_DONE
+        .wait_while(FULL_DECODES.lock().unwrap(), |running| {
+            *running >= FULL_DECODE_MAX_JOBS
+        })
+        .unwrap();
+    *running += 1;
+    drop(running);
+    let result = f();
+    *FULL_DECODES.lock().unwrap() -= 1;
+    FULL_DECODE_DONE.notify_one();
+    result
+}
+
+fn histogram_median(image: &DynamicImage) -> u32 {
+    let hist = imageproc::stats::histogram(&image.to_luma8());
+    let mut channel = hist.channels[0];
+    channel.sort();
+    channel[128]
+}
+
+// Percentage of pixels whose chroma is above `COLOR_CHROMA_THRESHOLD`
+fn color_ratio(image: &DynamicImage) -> f64 {
+    if !image.color().has_color() {
+        return 0.0;
+    }
+    let rgb = image.to_rgb8();
+    let colored = rgb
+        .pixels()
+        .filter(|p| {
+            let max = p.0.iter().max().unwrap();
+            let min = p.0.iter().min().unwrap();
+            max - min > COLOR_CHROMA_THRESHOLD
+        })
+        .count();
+    colored as f64 * 100.0 / (rgb.width() as f64 * rgb.height() as f64)
+}
+
+// Adaptive binarization, ink is 255
+fn ink_mask(luma: &GrayImage) -> GrayImage {
+    let local_mean = box_filter(luma, INK_BLOCK_RADIUS, INK_BLOCK_RADIUS);
+    GrayImage::from_fn(luma.width(), luma.height(), |x, y| {
+        let contrast = local_mean.get_pixel(x, y)[0] as i32 - luma.get_pixel(x, y)[0] as i32;
+        Luma([if contrast > INK_LOCAL_CONTRAST {
+            255
+        } else {
+            0
+        }])
+    })
+}
+
+// Percentage of ink pixels of an ink mask, without specks
+fn ink_ratio(ink: &GrayImage) -> f64 {
+    let labels = connected_components(ink, Connectivity::Eight, Luma([0]));
+    let mut sizes: Vec<usize> = Vec::new();
+    for label in labels
+        .pixels()
+        .map(|p| p[0] as usize)
+        .filter(|&label| label > 0)
+    {
+        if label >= sizes.len() {
+            sizes.resize(label + 1, 0);
+        }
+        sizes[label] += 1;
+    }
+    let ink_pixels: usize = sizes
+        .iter()
+        .filter(|&&size| size >= MIN_INK_COMPONENT)
+        .sum();
+    ink_pixels as f64 * 100.0 / (ink.width() as f64 * ink.height() as f64)
+}
+
+// keep the plan readable
+fn round2(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+fn downsample(image: DynamicImage) -> DynamicImage {
+    if image.width().max(image.height()) > ANALYSIS_MAX_DIMENSION {
+        image.thumbnail(ANALYSIS_MAX_DIMENSION, ANALYSIS_MAX_DIMENSION)
+    } else {
+        image
+    }
+}
+
+/// Open a page at most `ANALYSIS_MAX_DIMENSION` large.
+///
+/// JPEG scans are decoded at a reduced DCT scale, other formats are decoded
+/// then downsampled.
+pub fn open_reduced(path: &str, width: u32, height: u32) -> Result<DynamicImage, error::Error> {
+    decode_reduced(path, archive::read(path)?, width, height)
+}
+
+// `open_reduced` of the bytes already read from `path`
+fn decode_reduced(
+    path: &str,
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+) -> Result<DynamicImage, error::Error> {
+    let image = if ImageFormat::from_path(path)? == ImageFormat::Jpeg {
+        let factor = ANALYSIS_MAX_DIMENSION as f64 / width.max(height) as f64;
+        let mut decoder = JpegDecoder::new(Cursor::new(data))?;
+        if factor < 1.0 {
+            decoder.scale(
+                (width as f64 * factor) as u16,
+                (height as f64 * factor) as u16,
+            )?;
+        }
+        downsample(DynamicImage::from_decoder(decoder)?)
+    } else {
+        // the full page is dropped as soon as it is downsampled
+        full_decode(|| archive::decode_image(path, &data).map(downsample))?
+    };
+    Ok(image)
+}
+
+// `image` may be downsampled, `width` and `height` are the real dimensions of the page
+fn analyze_page(
+    path: &Path,
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+) -> (PageStats, Option<Lines>, Option<String>) {
+    let luma = image.to_luma8();
+    let ink = ink_mask(&luma);
+    let pixels = luma.width() as f64 * luma.height() as f64;
+    let luma_sum: f64 = luma.pixels().map(|p| p[0] as f64).sum();
+    // histogram counts scale with the pixel count, keep them comparable to full resolution
+    let scale = width as f64 * height as f64 / pixels;
+    let stats = PageStats {
+        width,
+        height,
+        histogram_median: (histogram_median(image) as f64 * scale) as u32,
+        mean_luma: round2(luma_sum / pixels),
+        ink_ratio: round2(ink_ratio(&ink)),
+        color_ratio: round2(color_ratio(image)),
+        phash: Some(phash::phash(image)),
+    };
+    let lines = orientation::detect(&ink);
+    debug!("Processing: {:?}, {:?}, {:?}", path.display(), stats, lines);
+    // made from the reduced page while it is decoded, the report needs no second pass
+    (stats, lines, report::thumbnail_of(image))
 }
 
+// Mark every page that looks like one of the few pages before it as an ignored duplicate.
+// Empty pages all look alike and are left alone
+fn flag_duplicates(records: &mut [PageRecord]) {
+    for i in 0..records.len() {
+        if records[i].class == PageClass::Empty {
+            continue;
+        }
+        let hash = match records[i].stats.as_ref().and_then(|stats| stats.phash) {
+            Some(hash) => hash,
+            None => continue,
+        };
+        let original = (i.saturating_sub(DUPLICATE_WINDOW)..i).rev().find(|&j| {
+            let candidate = &records[j];
+            if candidate.class == PageClass::Empty || candidate.duplicate_of.is_some() {
+                return false;
+            }
+            let limit = if j + 1 == i {
+                ADJACENT_DUPLICATE_DISTANCE
+            } else {
+                DUPLICATE_DISTANCE
+            };
+            candidate
+                .stats
+                .as_ref()
+                .and_then(|stats| stats.phash)
+                .is_some_and(|other| hash.distance(&other) <= limit)
+        });
+        if let Some(j) = original {
+            info!(
+                "{:?} is likely a duplicate of {:?}",
+                records[i].path, records[j].path
+            );
+            records[i].duplicate_of = Some(records[j].path.clone());
+            records[i].class = PageClass::Ignore;
+        }
+    }
+}
+
+// Record the rotation of the text pages whose lines do not run like the book's.
+// The layout of the book is the one of most text pages unless given
+fn flag_rotations(
+    records: &mut [PageRecord],
+    lines: &[Option<Lines>],
+    layout: Option<Layout>,
+) -> usize {
+    let layout = layout.or_else(|| {
+        orientation::dominant_layout(
+            records
+                .iter()
+                .zip(lines)
+                .filter(|(record, _)| record.class == PageClass::Text)
+                .filter_map(|(_, page_lines)| page_lines.as_ref()),
+        )
+    });
+    let layout = match layout {
+        Some(layout) => layout,
+        None => return 0,
+    };
+    info!("Text layout is {}", layout);
+
+    let mut rotated = 0;
+    for (record, page_lines) in records.iter_mut().zip(lines) {
+        let page_lines = match page_lines {
+            Some(page_lines) if record.class == PageClass::Text => page_lines,
+            _ => continue,
+        };
+        match orientation::rotation(page_lines, layout) {
+            Some(0) => (),
+            Some(degrees) => {
+                warn!(
+                    "{:?} looks rotated, it needs a {}° clockwise turn",
+                    record.path, degrees
+                );
+                record.rotation = Some(degrees);
+                rotated += 1;
+            }
+            None => warn!("{:?} looks sideways, but which way is unclear", record.path),
+        }
+    }
+    rotated
+}
+
+pub struct PlanOptions {
+    // `None` means calibrated from the pages
+    pub image_threadhold: Option<u32>,
+    // percentage of ink
+    pub empty_page_threadhold: f64,
+    pub color_threadhold: f64,
+    // when set, landscape spreads are split into pages written to this directory
+    pub spread_dir: Option<String>,
+    // number of pages analysed in parallel
+    pub jobs: usize,
+    // also take the pages of subdirectories
+    pub recursive: bool,
+    // direction of the text lines, `None` means detected from the pages
+    pub layout: Option<Layout>,
+    // learned from past plans, used instead of the threadholds when given
+    pub classifier: Option<Classifier>,
+}
+
+// The pages found in one input file, two when it is a split spread
+struct Analysis {
+    pages: Vec<(PathBuf, PageStats, Option<Lines>, Option<String>)>,
+    spread: Option<Spread>,
+}
+
+fn analyze_file(
+    path: &str,
+    book: &Path,
+    spread_names: &SpreadNames,
+    options: &PlanOptions,
+) -> Result<Analysis, error::Error> {
+    // the page is read once, inside an archive every read costs a seek and a copy
+    let data = archive::read(path)?;
+    let (width, height) = archive::decode_dimensions(path, &data)?;
+    match &options.spread_dir {
+        Some(spread_dir) if spread::is_spread(width, height) => {
+            info!("{:?} is likely a spread, splitting it", path);
+            let source = Path::new(path);
+            let name = spread_names.claim(source, book)?;
+            let dpi = pdf::resolution(&data);
+            // splitting needs the full resolution, the halves are downsampled right away
+            let (spread, split_pages) = full_decode(|| {
+                let image = archive::decode_image(path, &data)?;
+                let (spread, split_pages) =
+                    spread::split_spread(source, &name, &image, dpi, spread_dir)?;
+                let split_pages: Vec<_> = split_pages
+                    .into_iter()
+                    .map(|(page_path, page_image)| {
+                        let dimensions = (page_image.width(), page_image.height());
+                        (page_path, dimensions, downsample(page_image))
+                    })
+                    .collect();
+                Ok::<_, error::Error>((spread, split_pages))
+            })?;
+            let pages = split_pages
+                .into_iter()
+                .map(|(page_path, (page_width, page_height), reduced)| {
+                    let (stats, lines, thumbnail) =
+                        analyze_page(&page_path, &reduced, page_width, page_height);
+                    (page_path, stats, lines, thumbnail)
+                })
+                .collect();
+            Ok(Analysis {
+                pages,
+                spread: Some(spread),
+            })
+        }
+        _ => {
+            let image = decode_reduced(path, data, width, height)?;
+            let (stats, lines, thumbnail) = analyze_page(Path::new(path), &image, width, height);
+            Ok(Analysis {
+                pages: vec![(PathBuf::from(path), stats, lines, thumbnail)],
+                spread: None,
+            })
+        }
+    }
+}
+
+/// Generate the OCR plan of a directory.
+///
+/// Pages are taken in natural order, which is written to the plan as the page
+/// index followed by every later stage. They are analysed in parallel at a
+/// reduced resolution. When the image threadhold is not given, it is calibrated
+/// from the statistics of all pages in the directory. Pages with almost no ink
+/// are empty and colour pages are always filed as images, whatever their luma.
+/// With a classifier learned from past plans, its class is used instead when
+/// it has learned the class the threadholds give.
+/// Pages whose perceptual hash is close to one of the few pages before them
+/// are ignored as duplicates. Text pages whose lines run across the layout of the book or
+/// start on the wrong side are flagged as rotated. Files that are not images
+/// are skipped and listed in the plan. The thumbnails of the report are
+/// returned with the plan.
 pub fn plan(
     directory_input: &str,
-    image_threadhold: u32,
-    empty_page_threadhold: u32,
-) -> Result<String, error::Error> {
-    let mut empty_page = Vec::new();
-    let mut image_page = Vec::new();
-
-    for i in glob(&format!("{}/*", directory_input)).expect("Failed to read glob pattern") {
-        match i {
-            Ok(path) => {
-                let image = image::open(&path)?.to_luma8();
-                let hist = imageproc::stats::histogram(&image);
-                let mut channel = hist.channels[0];
-                channel.sort();
-                let mean = channel[128];
-                debug!("Processing: {:?}, mean = {}", &path.display(), mean);
-
-                let _page_propertise = if mean <= empty_page_threadhold {
-                    info!("{:?} is likely an empty page", &path.display());
-                    empty_page.push(String::from(path.to_str().unwrap()));
-                    PagePropertise::EmptyPage
-                } else if mean > image_threadhold {
-                    info!("{:?} is likely an image", &path.display());
-                    image_page.push(String::from(path.to_str().unwrap()));
-                    PagePropertise::Image
-                } else {
-                    trace!("{:?} is likely a normal text page", &path.display());
-                    PagePropertise::TextPage
-                };
+    options: &PlanOptions,
+) -> Result<(OcrPlan, Thumbnails), error::Error> {
+    let mut pages = Vec::new();
+    let mut spreads = Vec::new();
+    let listing = archive::list_pages(directory_input, options.recursive);
+    if !listing.skipped.is_empty() {
+        warn!(
+            "Skipped {} file(s) that are not pages, they are listed in the plan",
+            listing.skipped.len()
+        );
+    }
+    let book = Path::new(directory_input);
+    let spread_names = SpreadNames::default();
+    let analyses = progress::parallel_map("Analysing", &listing.pages, options.jobs, |path| {
+        analyze_file(path, book, &spread_names, options)
+    });
+    for analysis in analyses {
+        let analysis = analysis?;
+        pages.extend(analysis.pages);
+        spreads.extend(analysis.spread);
+    }
+
+    // colour and blank pages would skew the luma clusters
+    let medians: Vec<u32> = pages
+        .iter()
+        .filter(|(_, stats, _, _)| {
+            stats.color_ratio <= options.color_threadhold
+                && stats.ink_ratio > options.empty_page_threadhold
+        })
+        .map(|(_, stats, _, _)| stats.histogram_median)
+        .collect();
+    let calibration = calibrate::calibrate(&medians);
+    info!("Calibrated image threadhold: {}", calibration);
+
+    let image_threadhold = options.image_threadhold.unwrap_or(calibration.value);
+    info!(
+        "Using image_threadhold = {}, empty_threadhold = {}%",
+        image_threadhold, options.empty_page_threadhold
+    );
+
+    let mut records = Vec::new();
+    let mut lines = Vec::new();
+    let mut disagreements = 0;
+    let mut thumbnails = Thumbnails::new();
+    for (index, (path, stats, page_lines, thumbnail)) in pages.into_iter().enumerate() {
+        let threadhold_class = if stats.color_ratio > options.color_threadhold {
+            info!("{:?} is likely a colour image", &path.display());
+            PageClass::Color
+        } else if stats.ink_ratio <= options.empty_page_threadhold {
+            info!("{:?} is likely an empty page", &path.display());
+            PageClass::Empty
+        } else if stats.histogram_median > image_threadhold {
+            info!("{:?} is likely an image", &path.display());
+            PageClass::Image
+        } else {
+            trace!("{:?} is likely a normal text page", &path.display());
+            PageClass::Text
+        };
+        let class = match &options.classifier {
+            // a model that never learned the threadhold class could only ever contradict it
+            Some(classifier) if classifier.classes().contains(&threadhold_class) => {
+                let class = classifier.classify(&stats);
+                if class != threadhold_class {
+                    info!(
+                        "{:?} is classified {} by the model, {} by the threadholds",
+                        &path.display(),
+                        class,
+                        threadhold_class
+                    );
+                    disagreements += 1;
+                }
+                class
             }
-            Err(_e) => (),
+            _ => threadhold_class,
+        };
+        records.push(PageRecord {
+            path: String::from(path.to_str().unwrap()),
+            index,
+            class,
+            user_override: false,
+            duplicate_of: None,
+            rotation: None,
+            skew_angle: None,
+            processed_path: None,
+            filters: None,
+            crop: None,
+            crop_angle: None,
+            illustration_angle: None,
+            stats: Some(stats),
+            illustrations: None,
+        });
+        lines.push(page_lines);
+        if let Some(thumbnail) = thumbnail {
+            thumbnails.insert(String::from(path.to_str().unwrap()), thumbnail);
         }
     }
+    if let Some(classifier) = &options.classifier {
+        let classes: Vec<String> = classifier
+            .classes()
+            .iter()
+            .map(|class| class.to_string())
+            .collect();
+        info!(
+            "Pages classified by the learned model ({}), {} differ from the threadholds",
+            classes.join(", "),
+            disagreements
+        );
+    }
+    flag_duplicates(&mut records);
+    let rotated = flag_rotations(&mut records, &lines, options.layout);
+    if rotated > 0 {
+        warn!(
+            "{} page(s) look rotated, `preprocess --rotate` turns them upright",
+            rotated
+        );
+    }
 
-    let ocr_plan = OcrPlan::new(empty_page, image_page, Vec::new());
-    let toml = toml::to_string(&ocr_plan).unwrap();
-    Ok(toml)
+    let mut ocr_plan = OcrPlan::new(records, spreads);
+    ocr_plan.set_thresholds(Thresholds {
+        image: image_threadhold,
+        empty: options.empty_page_threadhold,
+        color: options.color_threadhold,
+        image_confidence: match options.image_threadhold {
+            Some(_) => None,
+            None => Some(round2(calibration.confidence)),
+        },
+    });
+    ocr_plan.set_skipped(listing.skipped);
+    Ok((ocr_plan, thumbnails))
 }
@@ -0,0 +1,632 @@
+use flate2::Crc;
+use image::codecs::jpeg::{JpegEncoder, PixelDensity};
+use image::{DynamicImage, GenericImage, GrayImage, ImageOutputFormat, Luma, Rgb, Rgba};
+use imageproc::contrast::otsu_level;
+use imageproc::distance_transform::Norm;
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use imageproc::morphology;
+use imageproc::region_labelling::{connected_components, Connectivity};
+use log::{debug, info, warn};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor};
+use std::path::Path;
+
+use crate::librote::archive;
+use crate::librote::error;
+use crate::librote::filter::{self, Filter};
+use crate::librote::orientation;
+use crate::librote::pdf;
+use crate::librote::progress;
+use crate::librote::{CropRect, Illustration, OcrPlan, PageClass, PageRecord, OCR_PLAN_PATH};
+
+const OUTPUT_JPEG_QUALITY: u8 = 95;
+// Pages binarized with Sauvola at once, a 600 dpi page needs about 500 MB
+const SAUVOLA_MAX_JOBS: usize = 4;
+
+// Skew is estimated on a downsampled page, which is plenty for a 0.02° precision
+const SKEW_ANALYSIS_DIMENSION: u32 = 1200;
+pub const DEFAULT_MAX_SKEW_ANGLE: f64 = 5.0;
+const COARSE_SKEW_STEP: f64 = 0.2;
+const FINE_SKEW_STEP: f64 = 0.02;
+// Below this, rotating would only blur the page
+const MIN_CORRECTED_ANGLE: f64 = 0.1;
+// A crop box or illustrations found at an angle further than this from the page's are found again
+const CROP_ANGLE_TOLERANCE: f64 = 0.01;
+
+const CROP_ANALYSIS_DIMENSION: u32 = 1200;
+// Edge rows/columns with more ink than this ratio are scanner borders
+const BORDER_INK_RATIO: f64 = 0.5;
+// Rows/columns with less ink than this ratio are margin, this ignores specks and dust
+const CONTENT_INK_RATIO: f64 = 0.01;
+pub const DEFAULT_CROP_PADDING: u32 = 32;
+
+const SEGMENT_ANALYSIS_DIMENSION: u32 = 1200;
+// Side of the square blocks a page is classified in, in analysed pixels
+const SEGMENT_BLOCK_SIZE: u32 = 12;
+// Blocks with more ink than this ratio are pictures, text lines stay well under it
+const PICTURE_INK_RATIO: f64 = 0.45;
+// Blocks with more mid-tone pixels than this ratio are shading, screentone or photos
+const PICTURE_MIDTONE_RATIO: f64 = 0.25;
+// Mid-tones are at least this far from both the paper and the ink
+const MIDTONE_MARGIN: u8 = 40;
+// Picture blocks this many blocks apart belong to the same illustration
+const PICTURE_GAP_BLOCKS: u8 = 2;
+// Pictures smaller than this part of the page, or thinner than
+// `MIN_ILLUSTRATION_SIDE` blocks, are ornaments
+const MIN_ILLUSTRATION_AREA: f64 = 0.02;
+const MIN_ILLUSTRATION_SIDE: u32 = 6;
+// A picture covering this much of the page is an image page, not an illustration
+const MAX_ILLUSTRATION_AREA: f64 = 0.8;
+const ILLUSTRATION_PADDING: u32 = 8;
+
+/// Extension of a derived page, PNG sources stay lossless and everything else becomes JPEG
+pub fn output_extension(source: &Path) -> &'static str {
+    match source.extension().and_then(OsStr::to_str) {
+        Some("png") => "png",
+        _ => "jpg",
+    }
+}
+
+/// Write an image as PNG when the path says so, otherwise as a high quality JPEG.
+///
+/// `dpi` is the resolution of the source, written to the file so the PDF stage
+/// keeps the physical size of the page.
+pub fn save_image(image: &DynamicImage, path: &Path, dpi: Option<f64>) -> Result<(), error::Error> {
+    if path.extension().and_then(OsStr::to_str) == Some("png") {
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+        if let Some(dpi) = dpi {
+            png = with_phys(png, dpi);
+        }
+        fs::write(path, png)?;
+    } else {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut encoder = JpegEncoder::new_with_quality(&mut writer, OUTPUT_JPEG_QUALITY);
+        if let Some(dpi) = dpi {
+            encoder.set_pixel_density(PixelDensity::dpi(dpi.round() as u16));
+        }
+        encoder.encode_image(image)?;
+    }
+    Ok(())
+}
+
+// Add a pHYs chunk right after the IHDR of a PNG, `image` does not write one
+fn with_phys(png: Vec<u8>, dpi: f64) -> Vec<u8> {
+    // signature, then the 13 bytes of IHDR with their length, type and CRC
+    let ihdr_end = 8 + 12 + 13;
+    let pixels_per_metre = ((dpi / 0.0254).round() as u32).to_be_bytes();
+    let mut data = Vec::with_capacity(13);
+    data.extend_from_slice(b"pHYs");
+    data.extend_from_slice(&pixels_per_metre);
+    data.extend_from_slice(&pixels_per_metre);
+    data.push(1);
+    let mut crc = Crc::new();
+    crc.update(&data);
+
+    let mut output = Vec::with_capacity(png.len() + 21);
+    output.extend_from_slice(&png[..ihdr_end]);
+    output.extend_from_slice(&9u32.to_be_bytes());
+    output.extend_from_slice(&data);
+    output.extend_from_slice(&crc.sum().to_be_bytes());
+    output.extend_from_slice(&png[ihdr_end..]);
+    output
+}
+
+pub struct PreprocessOptions {
+    // normalized pages are written here
+    pub output_dir: String,
+    // turn the pages the plan flags as rotated upright
+    pub rotate: bool,
+    pub deskew: bool,
+    pub max_skew_angle: f64,
+    pub crop: bool,
+    // pixels kept around the content
+    pub crop_padding: u32,
+    // crop the illustrations of text pages to their own image and blank them out
+    pub illustrations: bool,
+    // new filter chain of the book, `None` keeps the one of the plan
+    pub filters: Option<Vec<Filter>>,
+    pub jobs: usize,
+}
+
+// Grayscale copy of the page no larger than `dimension`, small pages are kept as is
+fn analysis_luma(image: &DynamicImage, dimension: u32) -> GrayImage {
+    if image.width().max(image.height()) > dimension {
+        image.thumbnail(dimension, dimension).to_luma8()
+    } else {
+        image.to_luma8()
+    }
+}
+
+struct Processed {
+    skew_angle: Option<f64>,
+    crop: Option<CropRect>,
+    crop_angle: Option<f64>,
+    illustration_angle: Option<f64>,
+    illustrations: Option<Vec<Illustration>>,
+    processed_path: Option<String>,
+}
+
+// Sum of squares of the row and column ink profiles after rotating by `angle`.
+// The profiles are sharpest when the text lines (horizontal) or columns
+// (tategaki) are axis aligned, so this works for both layouts
+fn projection_score(points: &[(f64, f64)], angle: f64, diagonal: usize) -> f64 {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let mut rows = vec![0u32; 2 * diagonal + 1];
+    let mut columns = vec![0u32; 2 * diagonal + 1];
+    for (x, y) in points {
+        let rotated_x = x * cos - y * sin;
+        let rotated_y = x * sin + y * cos;
+        columns[(rotated_x + diagonal as f64) as usize] += 1;
+        rows[(rotated_y + diagonal as f64) as usize] += 1;
+    }
+    rows.iter()
+        .chain(columns.iter())
+        .map(|&count| (count as f64).powi(2))
+        .sum()
+}
+
+fn best_angle(points: &[(f64, f64)], diagonal: usize, from: f64, to: f64, step: f64) -> f64 {
+    let steps = ((to - from) / step).round() as i32;
+    (0..=steps)
+        .map(|i| from + i as f64 * step)
+        .map(|angle| (angle, projection_score(points, angle, diagonal)))
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map_or(0.0, |(angle, _)| angle)
+}
+
+/// Estimate the clockwise rotation (in degrees) that straightens a page.
+///
+/// The ink pixels of the binarized page are rotated over a coarse then a fine
+/// range of angles, the one giving the sharpest projection profile wins.
+pub fn estimate_skew(image: &DynamicImage, max_angle: f64) -> f64 {
+    let luma = analysis_luma(image, SKEW_ANALYSIS_DIMENSION);
+    // ink is at or below the Otsu level, as in `imageproc::contrast::threshold`
+    let level = otsu_level(&luma);
+    let (width, height) = (luma.width() as f64, luma.height() as f64);
+    let points: Vec<(f64, f64)> = luma
+        .enumerate_pixels()
+        .filter(|(_, _, p)| p[0] <= level)
+        .map(|(x, y, _)| (x as f64 - width / 2.0, y as f64 - height / 2.0))
+        .collect();
+    if points.is_empty() {
+        return 0.0;
+    }
+
+    let diagonal = (width.hypot(height) / 2.0).ceil() as usize + 1;
+    let coarse = best_angle(&points, diagonal, -max_angle, max_angle, COARSE_SKEW_STEP);
+    best_angle(
+        &points,
+        diagonal,
+        coarse - COARSE_SKEW_STEP,
+        coarse + COARSE_SKEW_STEP,
+        FINE_SKEW_STEP,
+    )
+}
+
+fn rotate(image: &DynamicImage, angle: f64) -> DynamicImage {
+    let theta = angle.to_radians() as f32;
+    if image.color().has_color() {
+        DynamicImage::ImageRgb8(rotate_about_center(
+            &image.to_rgb8(),
+            theta,
+            Interpolation::Bilinear,
+            Rgb([255, 255, 255]),
+        ))
+    } else {
+        DynamicImage::ImageLuma8(rotate_about_center(
+            &image.to_luma8(),
+            theta,
+            Interpolation::Bilinear,
+            Luma([255]),
+        ))
+    }
+}
+
+fn ink_profile(ink: &GrayImage, level: u8, rows: bool, from: u32, to: u32) -> Vec<u32> {
+    let (outer, inner) = if rows {
+        (ink.height(), ink.width())
+    } else {
+        (ink.width(), ink.height())
+    };
+    (0..outer)
+        .map(|i| {
+            (from.min(inner)..to.min(inner))
+                .filter(|&j| {
+                    let (x, y) = if rows { (j, i) } else { (i, j) };
+                    ink.get_pixel(x, y)[0] <= level
+                })
+                .count() as u32
+        })
+        .collect()
+}
+
+// First and one past the last index in `from..to` above `threshold`
+fn content_span(profile: &[u32], from: u32, to: u32, threshold: f64) -> Option<(u32, u32)> {
+    let is_content = |i: &u32| profile[*i as usize] as f64 > threshold;
+    let first = (from..to).find(is_content)?;
+    let last = (from..to).rev().find(is_content)?;
+    Some((first, last + 1))
+}
+
+// Number of edge entries of `profile` above `threshold`, from the start or the end
+fn border_width(profile: &[u32], threshold: f64, from_end: bool) -> u32 {
+    let over = |count: &&u32| **count as f64 > threshold;
+    if from_end {
+        profile.iter().rev().take_while(over).count() as u32
+    } else {
+        profile.iter().take_while(over).count() as u32
+    }
+}
+
+/// Find the content of a page without scanner borders and excess margin.
+///
+/// Edge rows and columns that are mostly ink are scanner borders, then the
+/// content box is the span of rows and columns with some ink inside them,
+/// grown by `padding` pixels. `None` for a blank page.
+pub fn find_content_box(image: &DynamicImage, padding: u32) -> Option<CropRect> {
+    let small = analysis_luma(image, CROP_ANALYSIS_DIMENSION);
+    let (width, height) = small.dimensions();
+    let scale = image.width() as f64 / width as f64;
+    let level = otsu_level(&small);
+
+    let rows = ink_profile(&small, level, true, 0, width);
+    let columns = ink_profile(&small, level, false, 0, height);
+    let top = border_width(&rows, width as f64 * BORDER_INK_RATIO, false);
+    let bottom = height - border_width(&rows, width as f64 * BORDER_INK_RATIO, true);
+    let left = border_width(&columns, height as f64 * BORDER_INK_RATIO, false);
+    let right = width - border_width(&columns, height as f64 * BORDER_INK_RATIO, true);
+    if top >= bottom || left >= right {
+        return None;
+    }
+
+    // only look inside the borders from now on
+    let rows = ink_profile(&small, level, true, left, right);
+    let columns = ink_profile(&small, level, false, top, bottom);
+    let (content_top, content_bottom) = content_span(
+        &rows,
+        top,
+        bottom,
+        (right - left) as f64 * CONTENT_INK_RATIO,
+    )?;
+    let (content_left, content_right) = content_span(
+        &columns,
+        left,
+        right,
+        (bottom - top) as f64 * CONTENT_INK_RATIO,
+    )?;
+
+    let to_full = |value: u32| (value as f64 * scale).round() as u32;
+    let x = to_full(content_left)
+        .saturating_sub(padding)
+        .max(to_full(left));
+    let y = to_full(content_top)
+        .saturating_sub(padding)
+        .max(to_full(top));
+    let x_end = (to_full(content_right) + padding)
+        .min(to_full(right))
+        .min(image.width());
+    let y_end = (to_full(content_bottom) + padding)
+        .min(to_full(bottom))
+        .min(image.height());
+    Some(CropRect {
+        x,
+        y,
+        width: x_end - x,
+        height: y_end - y,
+    })
+}
+
+// Luma of the paper and of the ink, the 90th and 5th percentile of the page
+fn paper_and_ink(luma: &GrayImage) -> (u8, u8) {
+    let mut values: Vec<u8> = luma.pixels().map(|p| p[0]).collect();
+    values.sort_unstable();
+    (values[values.len() * 9 / 10], values[values.len() / 20])
+}
+
+/// Find the illustrations of a text page.
+///
+/// The page is cut in blocks, the ones dense in ink or in mid-tones are
+/// pictures while text blocks are sparse strokes on paper. Neighbouring
+/// picture blocks are joined, and each group large enough is an illustration.
+/// The rectangles are in pixels of `image`, grown by a few pixels.
+pub fn find_illustrations(image: &DynamicImage) -> Vec<CropRect> {
+    let small = analysis_luma(image, SEGMENT_ANALYSIS_DIMENSION);
+    let scale = image.width() as f64 / small.width() as f64;
+    let level = otsu_level(&small);
+    let (paper, ink) = paper_and_ink(&small);
+    let is_midtone = |value: u8| {
+        value as i32 > ink as i32 + MIDTONE_MARGIN as i32
+            && (value as i32) < paper as i32 - MIDTONE_MARGIN as i32
+    };
+
+    let columns = small.width().div_ceil(SEGMENT_BLOCK_SIZE);
+    let rows = small.height().div_ceil(SEGMENT_BLOCK_SIZE);
+    let blocks = GrayImage::from_fn(columns, rows, |column, row| {
+        let (mut dark, mut midtone, mut total) = (0, 0, 0);
+        for y in row * SEGMENT_BLOCK_SIZE..((row + 1) * SEGMENT_BLOCK_SIZE).min(small.height()) {
+            for x in
+                column * SEGMENT_BLOCK_SIZE..((column + 1) * SEGMENT_BLOCK_SIZE).min(small.width())
+            {
+                let value = small.get_pixel(x, y)[0];
+                if value <= level {
+                    dark += 1;
+                }
+                if is_midtone(value) {
+                    midtone += 1;
+                }
+                total += 1;
+            }
+        }
+        let picture = dark as f64 > total as f64 * PICTURE_INK_RATIO
+            || midtone as f64 > total as f64 * PICTURE_MIDTONE_RATIO;
+        Luma([if picture { 255 } else { 0 }])
+    });
+    // lone dense blocks (bold glyphs) and thin strips (scanner borders, rules) go first
+    let blocks = morphology::open(&blocks, Norm::LInf, 1);
+    let blocks = morphology::close(&blocks, Norm::LInf, PICTURE_GAP_BLOCKS);
+
+    // bounding box of each group of picture blocks, in blocks
+    let labels = connected_components(&blocks, Connectivity::Eight, Luma([0]));
+    let mut boxes: Vec<Option<(u32, u32, u32, u32)>> = Vec::new();
+    for (x, y, label) in labels.enumerate_pixels() {
+        let label = label[0] as usize;
+        if label == 0 {
+            continue;
+        }
+        if label >= boxes.len() {
+            boxes.resize(label + 1, None);
+        }
+        boxes[label] = Some(match boxes[label] {
+            Some((left, top, right, bottom)) => {
+                (left.min(x), top.min(y), right.max(x + 1), bottom.max(y + 1))
+            }
+            None => (x, y, x + 1, y + 1),
+        });
+    }
+
+    let page_blocks = (columns * rows) as f64;
+    let to_full = |block: u32| (block as f64 * SEGMENT_BLOCK_SIZE as f64 * scale).round() as u32;
+    boxes
+        .into_iter()
+        .flatten()
+        .filter(|(left, top, right, bottom)| {
+            let (width, height) = (right - left, bottom - top);
+            let area = (width * height) as f64 / page_blocks;
+            width.min(height) >= MIN_ILLUSTRATION_SIDE
+                && (MIN_ILLUSTRATION_AREA..=MAX_ILLUSTRATION_AREA).contains(&area)
+        })
+        .map(|(left, top, right, bottom)| {
+            let x = to_full(left).saturating_sub(ILLUSTRATION_PADDING);
+            let y = to_full(top).saturating_sub(ILLUSTRATION_PADDING);
+            let x_end = (to_full(right) + ILLUSTRATION_PADDING).min(image.width());
+            let y_end = (to_full(bottom) + ILLUSTRATION_PADDING).min(image.height());
+            CropRect {
+                x,
+                y,
+                width: x_end - x,
+                height: y_end - y,
+            }
+        })
+        .collect()
+}
+
+// Paint a rectangle of the page white, so OCR does not read the picture
+fn blank_out(image: &mut DynamicImage, rect: &CropRect) {
+    let x_end = (rect.x + rect.width).min(image.width());
+    let y_end = (rect.y + rect.height).min(image.height());
+    for y in rect.y..y_end {
+        for x in rect.x..x_end {
+            image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+        }
+    }
+}
+
+// Crop box and illustrations already in the record were reviewed (or edited) and are kept,
+// the filters of the record override `book_filters`
+fn preprocess_page(
+    record: &PageRecord,
+    book_filters: &[Filter],
+    options: &PreprocessOptions,
+) -> Result<Processed, error::Error> {
+    let path = record.path.as_str();
+    let data = archive::read(path)?;
+    let dpi = pdf::resolution(&data);
+    let mut image = archive::decode_image(path, &data)?;
+    let mut changed = false;
+    // clockwise degrees the page is turned before cropping
+    let mut turned = 0.0;
+
+    if let (true, Some(degrees)) = (options.rotate, record.rotation) {
+        debug!("{} is turned {}° clockwise", path, degrees);
+        image = orientation::rotate(&image, degrees);
+        changed = true;
+        turned += degrees as f64;
+    }
+
+    let mut skew_angle = None;
+    if options.deskew {
+        let angle = estimate_skew(&image, options.max_skew_angle);
+        debug!("{} is skewed by {:.2}°", path, angle);
+        if angle.abs() >= MIN_CORRECTED_ANGLE {
+            image = rotate(&image, angle);
+            changed = true;
+            turned += angle;
+        }
+        skew_angle = Some(angle);
+    }
+
+    let source = Path::new(path);
+    // the index keeps pages of different directories with the same file name apart
+    let stem = format!(
+        "{:04}_{}",
+        record.index,
+        source.file_stem().and_then(OsStr::to_str).unwrap()
+    );
+    // regions in the record were reviewed and are applied on every run, so the
+    // page stays blanked out; they are only looked for with `--illustrations`
+    let rects: Option<Vec<CropRect>> = match (&record.illustrations, record.illustration_angle) {
+        // the regions are in the coordinates of a page turned another way
+        (Some(_), Some(angle)) if (angle - turned).abs() > CROP_ANGLE_TOLERANCE => {
+            warn!(
+                "The illustrations of {} were found with the page turned {:.2}°, it is {:.2}° now, finding them again",
+                path, angle, turned
+            );
+            Some(find_illustrations(&image))
+        }
+        (Some(illustrations), _) => Some(
+            illustrations
+                .iter()
+                .map(|illustration| illustration.rect.clone())
+                .collect(),
+        ),
+        (None, _) if options.illustrations => Some(find_illustrations(&image)),
+        (None, _) => None,
+    };
+    let mut illustrations = None;
+    if let Some(rects) = rects {
+        let mut cropped = Vec::new();
+        for (i, rect) in rects.into_iter().enumerate() {
+            debug!("{} has an illustration at {:?}", path, rect);
+            let output_path = Path::new(&options.output_dir).join(format!(
+                "{}_illust{}.{}",
+                stem,
+                i + 1,
+                output_extension(source)
+            ));
+            save_image(
+                &image.crop_imm(rect.x, rect.y, rect.width, rect.height),
+                &output_path,
+                dpi,
+            )?;
+            blank_out(&mut image, &rect);
+            changed = true;
+            cropped.push(Illustration {
+                image_path: Some(String::from(output_path.to_str().unwrap())),
+                rect,
+            });
+        }
+        illustrations = Some(cropped);
+    }
+
+    let mut crop = None;
+    if options.crop {
+        let rect = match (&record.crop, record.crop_angle) {
+            // the box is in the coordinates of a page turned another way
+            (Some(_), Some(angle)) if (angle - turned).abs() > CROP_ANGLE_TOLERANCE => {
+                warn!(
+                    "The crop box of {} was found with the page turned {:.2}°, it is {:.2}° now, finding it again",
+                    path, angle, turned
+                );
+                find_content_box(&image, options.crop_padding)
+            }
+            (Some(rect), _) => Some(rect.clone()),
+            (None, _) => find_content_box(&image, options.crop_padding),
+        };
+        if let Some(rect) = &rect {
+            debug!("{} content box is {:?}", path, rect);
+            if rect.width < image.width() || rect.height < image.height() {
+                image = image.crop_imm(rect.x, rect.y, rect.width, rect.height);
+                changed = true;
+            }
+        }
+        crop = rect;
+    }
+
+    let filters = record.filters.as_deref().unwrap_or(book_filters);
+    if !filters.is_empty() {
+        debug!("{} is filtered with {:?}", path, filters);
+        image = filter::apply(image, filters);
+        changed = true;
+    }
+
+    let processed_path = if changed {
+        // black and white pages compress far better as PNG
+        let extension = if filter::is_binary(filters) {
+            "png"
+        } else {
+            output_extension(source)
+        };
+        let output_path = Path::new(&options.output_dir).join(format!("{}.{}", stem, extension));
+        save_image(&image, &output_path, dpi)?;
+        Some(String::from(output_path.to_str().unwrap()))
+    } else {
+        None
+    };
+    Ok(Processed {
+        skew_angle,
+        crop_angle: crop.as_ref().map(|_| turned),
+        crop,
+        illustration_angle: illustrations.as_ref().map(|_| turned),
+        illustrations,
+        processed_path,
+    })
+}
+
+/// Normalize every text page of the plan.
+///
+/// The normalized images are written to `output_dir` and recorded in the plan,
+/// so the PDF stage uses them instead of the originals. Pages that need no
+/// change keep using their original image. The filter chain of the book is
+/// applied last, a page with its own `filters` uses those instead.
+pub fn preprocess(options: &PreprocessOptions) -> Result<(), error::Error> {
+    let mut ocr_plan = OcrPlan::load(OCR_PLAN_PATH);
+    if let Some(filters) = &options.filters {
+        ocr_plan.set_filters(filters.clone());
+    }
+    let book_filters = ocr_plan.filters().to_vec();
+    let text_pages: Vec<PageRecord> = ocr_plan
+        .ordered_records()
+        .into_iter()
+        .filter(|record| record.class == PageClass::Text)
+        .cloned()
+        .collect();
+    if text_pages.is_empty() {
+        warn!("No text page in the plan, run `plan --update` to upgrade an old plan");
+        return Ok(());
+    }
+
+    // the summed area tables of Sauvola take 12 bytes per pixel of the page
+    let sauvola = text_pages.iter().any(|record| {
+        record
+            .filters
+            .as_deref()
+            .unwrap_or(&book_filters)
+            .contains(&Filter::Sauvola)
+    });
+    let jobs = if sauvola {
+        options.jobs.min(SAUVOLA_MAX_JOBS)
+    } else {
+        options.jobs
+    };
+
+    fs::create_dir_all(&options.output_dir)?;
+    let results = progress::parallel_map("Preprocessing", &text_pages, jobs, |record| {
+        preprocess_page(record, &book_filters, options)
+    });
+
+    let mut illustration_count = 0;
+    for (page, result) in text_pages.iter().zip(results) {
+        let processed = result?;
+        let record = ocr_plan.record_mut(&page.path).unwrap();
+        record.skew_angle = processed.skew_angle;
+        if options.crop {
+            record.crop = processed.crop;
+            record.crop_angle = processed.crop_angle;
+        }
+        illustration_count += processed.illustrations.as_ref().map_or(0, Vec::len);
+        record.illustrations = processed.illustrations;
+        record.illustration_angle = processed.illustration_angle;
+        record.processed_path = processed.processed_path;
+    }
+    ocr_plan.save(OCR_PLAN_PATH)?;
+    if illustration_count > 0 {
+        info!(
+            "{} illustration(s) cropped to `{}` and blanked out of their page",
+            illustration_count, options.output_dir
+        );
+    }
+    info!("Finished preprocessing {} text pages", text_pages.len());
+    Ok(())
+}
@@ -1,13 +1,24 @@
 use log::{debug, info};
 use regex::Regex;
 use scraper::{Html, Selector};
+use std::collections::HashSet;
 use std::fs::{self, OpenOptions};
 use std::io::Write;
 use std::process::Command;
 
-pub fn parse_ocr_html(num_chunk: u8, font_size_threadhold: u8) {
+use crate::librote::chunks::{ChunkManifest, ChunkPage};
+use crate::librote::marker::{self, MarkerReader};
+
+/// Write the raw text of every chunk, a page ending where its marker is read.
+///
+/// Returns the pages whose marker is missing from the OCR text, their text
+/// was lost or ended up with the next page.
+pub fn parse_ocr_html(manifest: &ChunkManifest, font_size_threadhold: u8) -> Vec<(u32, ChunkPage)> {
     let font_size_regex = Regex::new("font-size:(\\d+)pt").unwrap();
-    for i in 1..=num_chunk {
+    let marker_reader = MarkerReader::new();
+    let mut missing = Vec::new();
+    for chunk in manifest.chunks() {
+        let i = chunk.index;
         let html = fs::read_to_string(format!("tidy_{:02}.html", i)).unwrap();
 
         let document = Html::parse_document(&html);
@@ -36,16 +47,27 @@ pub fn parse_ocr_html(num_chunk: u8, font_size_threadhold: u8) {
         proc_text_vec.push(current_line);
 
         let mut final_text = String::new();
+        let mut found = HashSet::new();
         for (index, font_size) in font_size_vec.into_iter().enumerate() {
-            if font_size > font_size_threadhold {
-                let text = &proc_text_vec[index + 1];
-                if text.contains("PAGE") {
-                    final_text.push_str("-----");
-                } else if text.contains("MARKER") {
-                    final_text.push_str("-----\n");
-                } else {
-                    final_text.push_str(&format!("{}\n", text));
-                }
+            let text = &proc_text_vec[index + 1];
+            // markers are kept whatever size OCR gives them
+            if let Some(number) = marker_reader.read(text) {
+                debug!("Found the marker of page {}", number);
+                found.insert(number);
+                final_text.push_str("-----\n");
+            } else if font_size > font_size_threadhold {
+                final_text.push_str(&format!("{}\n", text));
+            }
+        }
+        for page in &chunk.pages {
+            if !found.contains(&page.marker) {
+                info!(
+                    "The marker of `{}` ({}) is missing from chunk {}",
+                    page.path,
+                    marker::text(page.marker),
+                    i
+                );
+                missing.push((i, page.clone()));
             }
         }
 
@@ -57,17 +79,18 @@ pub fn parse_ocr_html(num_chunk: u8, font_size_threadhold: u8) {
         write!(output_file, "{}", final_text).expect("could not write output to `raw.txt`");
         info!("Finished writing raw_{:02}.txt", i);
     }
+    missing
 }
 
-pub fn tidy(num_chunk: u8) {
-    for chunk_number in 1..=num_chunk {
+pub fn tidy(manifest: &ChunkManifest) {
+    for chunk in manifest.chunks() {
         Command::new("tidy")
             .arg("--show-warnings")
             .arg("false")
             .arg("-quiet")
             .arg("-output")
-            .arg(format!("tidy_{:02}.html", chunk_number))
-            .arg(format!("ocr_{:02}.html", chunk_number))
+            .arg(format!("tidy_{:02}.html", chunk.index))
+            .arg(chunk.ocr_path())
             .status()
             .expect("Could not spawn `tidy`");
     }
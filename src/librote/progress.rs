@@ -0,0 +1,85 @@
+use std::io::{stderr, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A single line progress indicator on stderr with the estimated time left
+pub struct Progress {
+    label: &'static str,
+    total: usize,
+    done: usize,
+    start: Instant,
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{:02}:{:02}", seconds / 60, seconds % 60)
+}
+
+impl Progress {
+    pub fn new(label: &'static str, total: usize) -> Self {
+        Self {
+            label,
+            total,
+            done: 0,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.done += 1;
+        let elapsed = self.start.elapsed();
+        let remaining = elapsed.mul_f64((self.total - self.done) as f64 / self.done as f64);
+        eprint!(
+            "\r{}: {}/{} pages, elapsed {}, ETA {}",
+            self.label,
+            self.done,
+            self.total,
+            format_duration(elapsed),
+            format_duration(remaining)
+        );
+        if self.done == self.total {
+            eprintln!();
+        }
+        stderr().flush().ok();
+    }
+}
+
+/// Run `f` over every item on `jobs` threads with a progress indicator.
+///
+/// Results keep the order of `items`.
+pub fn parallel_map<T, R, F>(label: &'static str, items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let total = items.len();
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let mut results: Vec<Option<R>> = (0..total).map(|_| None).collect();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            let sender = sender.clone();
+            let (next, f) = (&next, &f);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= total {
+                    break;
+                }
+                sender.send((i, f(&items[i]))).unwrap();
+            });
+        }
+        drop(sender);
+
+        let mut progress = Progress::new(label, total);
+        for (i, result) in receiver {
+            results[i] = Some(result);
+            progress.tick();
+        }
+    });
+
+    results.into_iter().map(|result| result.unwrap()).collect()
+}
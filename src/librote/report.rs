@@ -0,0 +1,285 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+use log::warn;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+
+use crate::librote::archive;
+use crate::librote::error;
+use crate::librote::plan;
+use crate::librote::progress;
+use crate::librote::{OcrPlan, PageClass, PageRecord, Thresholds};
+
+pub const REPORT_PATH: &str = "ocr_plan.html";
+
+const THUMBNAIL_SIZE: u32 = 240;
+const THUMBNAIL_JPEG_QUALITY: u8 = 70;
+// Pages this close (relative) to a threshold are worth a second look
+const BORDERLINE_MARGIN: f64 = 0.2;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 1em; background: #f4f4f4; }
+.legend span, .class { padding: 0.1em 0.5em; border-radius: 0.3em; }
+.grid { display: grid; grid-template-columns: repeat(auto-fill, minmax(260px, 1fr)); gap: 0.8em; }
+.page { background: white; border: 4px solid; padding: 0.4em; font-size: 0.8em; }
+.page.borderline { border-style: dashed; }
+.page img { display: block; margin: auto; max-width: 100%; height: 240px; object-fit: contain; }
+.page table { width: 100%; }
+.page .nearest { font-weight: bold; }
+.text { border-color: #999; background-color: #eee; }
+.image { border-color: #2a6fdb; background-color: #d6e4fa; }
+.color { border-color: #a23cc4; background-color: #f0d9f7; }
+.empty { border-color: #d9a400; background-color: #fbf0c8; }
+.ignore { border-color: #d33; background-color: #f8d4d4; }
+.page.text, .page.image, .page.color, .page.empty, .page.ignore { background-color: white; }
+";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Relative distance of a statistic to its threshold, negative below it
+pub fn margin(value: f64, threshold: f64) -> f64 {
+    if threshold == 0.0 {
+        return if value == 0.0 { 0.0 } else { 1.0 };
+    }
+    (value - threshold) / threshold
+}
+
+/// Thumbnails of the pages by path, as data URIs
+pub type Thumbnails = HashMap<String, String>;
+
+/// A small JPEG of a page image as a data URI
+pub fn thumbnail_of(image: &DynamicImage) -> Option<String> {
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, THUMBNAIL_JPEG_QUALITY)
+        .encode_image(&image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE))
+        .ok()?;
+    Some(format!("data:image/jpeg;base64,{}", base64::encode(jpeg)))
+}
+
+// The thumbnail of a page not analysed in this run, `None` if the page cannot be read
+fn thumbnail(record: &PageRecord) -> Option<String> {
+    let (width, height) = match &record.stats {
+        Some(stats) => (stats.width, stats.height),
+        None => archive::image_dimensions(&record.path).ok()?,
+    };
+    match plan::open_reduced(&record.path, width, height) {
+        Ok(image) => thumbnail_of(&image),
+        Err(e) => {
+            warn!("Could not make a thumbnail of {}: {}", record.path, e);
+            None
+        }
+    }
+}
+
+fn write_page(
+    html: &mut String,
+    record: &PageRecord,
+    thumbnail: Option<String>,
+    thresholds: Option<&Thresholds>,
+) {
+    // (label, value, threshold, margin)
+    let mut rows = Vec::new();
+    if let (Some(stats), Some(thresholds)) = (&record.stats, thresholds) {
+        rows.push((
+            "image",
+            stats.histogram_median.to_string(),
+            thresholds.image.to_string(),
+            margin(stats.histogram_median as f64, thresholds.image as f64),
+        ));
+        rows.push((
+            "empty",
+            format!("{}%", stats.ink_ratio),
+            format!("{}%", thresholds.empty),
+            margin(stats.ink_ratio, thresholds.empty),
+        ));
+        rows.push((
+            "colour",
+            format!("{}%", stats.color_ratio),
+            format!("{}%", thresholds.color),
+            margin(stats.color_ratio, thresholds.color),
+        ));
+    }
+    let nearest = rows
+        .iter()
+        .map(|row| row.3.abs())
+        .fold(f64::INFINITY, f64::min);
+    let borderline = nearest < BORDERLINE_MARGIN && !record.user_override;
+
+    writeln!(
+        html,
+        "<div class=\"page {}{}\" id=\"page-{}\">",
+        record.class,
+        if borderline { " borderline" } else { "" },
+        record.index
+    )
+    .unwrap();
+    match thumbnail {
+        Some(uri) => writeln!(html, "<img src=\"{}\" loading=\"lazy\">", uri).unwrap(),
+        None => writeln!(html, "<p>(could not read the page)</p>").unwrap(),
+    }
+    writeln!(
+        html,
+        "<p>#{} <span class=\"class {}\">{}</span>{} <code>{}</code></p>",
+        record.index,
+        record.class,
+        record.class,
+        if record.user_override {
+            " (edited)"
+        } else {
+            ""
+        },
+        escape(&record.path)
+    )
+    .unwrap();
+    if let Some(original) = &record.duplicate_of {
+        writeln!(
+            html,
+            "<p>duplicate of <code>{}</code></p>",
+            escape(original)
+        )
+        .unwrap();
+    }
+    match record.illustrations.as_deref() {
+        Some([]) | None => (),
+        Some(illustrations) => writeln!(
+            html,
+            "<p>{} illustration(s) cropped out</p>",
+            illustrations.len()
+        )
+        .unwrap(),
+    }
+    if let Some(degrees) = record.rotation {
+        writeln!(html, "<p>rotated, needs a {}° clockwise turn</p>", degrees).unwrap();
+    }
+    if let Some(stats) = &record.stats {
+        writeln!(
+            html,
+            "<p>{}x{}, histogram median {}, mean luma {}</p>",
+            stats.width, stats.height, stats.histogram_median, stats.mean_luma
+        )
+        .unwrap();
+    }
+    if !rows.is_empty() {
+        html.push_str("<table><tr><th></th><th>value</th><th>threshold</th><th>margin</th></tr>\n");
+        for (label, value, threshold, row_margin) in &rows {
+            writeln!(
+                html,
+                "<tr{}><td>{}</td><td>{}</td><td>{}</td><td>{:+.0}%</td></tr>",
+                if row_margin.abs() == nearest {
+                    " class=\"nearest\""
+                } else {
+                    ""
+                },
+                label,
+                value,
+                threshold,
+                row_margin * 100.0
+            )
+            .unwrap();
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</div>\n");
+}
+
+/// Write a self-contained HTML contact sheet of the plan.
+///
+/// Every page is shown as a thumbnail in reading order, coloured by class with
+/// its statistics and their distance to the thresholds. Pages close to a
+/// threshold get a dashed border, those are the likely misclassifications.
+/// Only the pages missing from `thumbnails` are read again.
+pub fn write_report(
+    ocr_plan: &OcrPlan,
+    path: &str,
+    jobs: usize,
+    thumbnails: &Thumbnails,
+) -> Result<(), error::Error> {
+    let records = ocr_plan.ordered_records();
+    let thumbnails =
+        progress::parallel_map("Thumbnails", &records, jobs, |record| {
+            match thumbnails.get(&record.path) {
+                Some(thumbnail) => Some(thumbnail.clone()),
+                None => thumbnail(record),
+            }
+        });
+
+    let mut html = String::new();
+    writeln!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>OCR plan</title>\n<style>{}</style>\n</head>\n<body>",
+        STYLE
+    )
+    .unwrap();
+
+    html.push_str("<p class=\"legend\">");
+    for class in &[
+        PageClass::Text,
+        PageClass::Image,
+        PageClass::Color,
+        PageClass::Empty,
+        PageClass::Ignore,
+    ] {
+        let count = records
+            .iter()
+            .filter(|record| record.class == *class)
+            .count();
+        write!(
+            html,
+            "<span class=\"{}\">{}: {}</span> ",
+            class, class, count
+        )
+        .unwrap();
+    }
+    html.push_str("</p>\n");
+    if let Some(thresholds) = ocr_plan.thresholds() {
+        let calibration = thresholds
+            .image_confidence
+            .map_or(String::new(), |confidence| {
+                format!(" (calibration confidence {:.2})", confidence)
+            });
+        writeln!(
+            html,
+            "<p>Thresholds: image histogram median {}{}, empty {}% ink, colour {}% coloured pixels. Dashed pages are within {}% of one.</p>",
+            thresholds.image,
+            calibration,
+            thresholds.empty,
+            thresholds.color,
+            BORDERLINE_MARGIN * 100.0
+        )
+        .unwrap();
+    }
+
+    if !ocr_plan.skipped().is_empty() {
+        writeln!(
+            html,
+            "<details><summary>{} skipped file(s)</summary><ul>",
+            ocr_plan.skipped().len()
+        )
+        .unwrap();
+        for skipped in ocr_plan.skipped() {
+            writeln!(
+                html,
+                "<li><code>{}</code>: {}</li>",
+                escape(&skipped.path),
+                escape(&skipped.reason)
+            )
+            .unwrap();
+        }
+        html.push_str("</ul></details>\n");
+    }
+
+    html.push_str("<div class=\"grid\">\n");
+    for (record, thumbnail) in records.iter().zip(thumbnails) {
+        write_page(&mut html, record, thumbnail, ocr_plan.thresholds());
+    }
+    html.push_str("</div>\n</body>\n</html>\n");
+
+    fs::write(path, html)?;
+    Ok(())
+}
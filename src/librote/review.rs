@@ -0,0 +1,319 @@
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use image::RgbImage;
+use log::warn;
+use std::env;
+use std::io::{stdout, Stdout, Write};
+
+use crate::librote::archive;
+use crate::librote::error;
+use crate::librote::plan;
+use crate::librote::report;
+use crate::librote::{OcrPlan, PageClass, PageRecord};
+
+// Lines above the preview: page, class, stats, margins and a blank line
+const HEADER_LINES: u16 = 5;
+// Line below the preview with the key help
+const FOOTER_LINES: u16 = 1;
+
+const HELP: &str =
+    "t text  i image  c color  e empty  x ignore | \u{2190}/\u{2192} move  w write  q write and quit  Esc quit";
+
+// Raw mode and the alternate screen, restored when dropped even on error
+struct Screen {
+    out: Stdout,
+}
+
+impl Screen {
+    fn enter() -> Result<Self, error::Error> {
+        terminal::enable_raw_mode()?;
+        let mut out = stdout();
+        execute!(out, EnterAlternateScreen, Hide)?;
+        Ok(Self { out })
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(self.out, ResetColor, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// Half block previews need 24 bit colours
+fn supports_preview() -> bool {
+    env::var("COLORTERM").is_ok_and(|value| value == "truecolor" || value == "24bit")
+}
+
+// The page reduced to fit `columns` x `rows` cells, two pixels per cell
+fn preview(record: &PageRecord, columns: u16, rows: u16) -> Option<RgbImage> {
+    let (width, height) = match &record.stats {
+        Some(stats) => (stats.width, stats.height),
+        None => archive::image_dimensions(&record.path).ok()?,
+    };
+    match plan::open_reduced(&record.path, width, height) {
+        Ok(image) => Some(image.thumbnail(columns as u32, rows as u32 * 2).to_rgb8()),
+        Err(e) => {
+            warn!("Could not preview {}: {}", record.path, e);
+            None
+        }
+    }
+}
+
+fn class_color(class: PageClass) -> Color {
+    match class {
+        PageClass::Text => Color::Grey,
+        PageClass::Image => Color::Blue,
+        PageClass::Color => Color::Magenta,
+        PageClass::Empty => Color::Yellow,
+        PageClass::Ignore => Color::Red,
+    }
+}
+
+struct Reviewer {
+    ocr_plan: OcrPlan,
+    // page paths in reading order
+    paths: Vec<String>,
+    current: usize,
+    // (page, columns, rows) the cached preview was made for
+    preview_key: Option<(usize, u16, u16)>,
+    preview: Option<RgbImage>,
+    modified: bool,
+    status: String,
+}
+
+impl Reviewer {
+    fn record(&self) -> &PageRecord {
+        self.ocr_plan.record(&self.paths[self.current]).unwrap()
+    }
+
+    fn reclassify(&mut self, class: PageClass) {
+        let path = self.paths[self.current].clone();
+        let record = self.ocr_plan.record_mut(&path).unwrap();
+        if record.class != class {
+            self.status = format!("{}: {} -> {}", path, record.class, class);
+            record.class = class;
+            record.user_override = true;
+            // no longer ignored, so no longer a duplicate
+            if class != PageClass::Ignore {
+                record.duplicate_of = None;
+            }
+            self.modified = true;
+        }
+        self.step(1);
+    }
+
+    fn step(&mut self, offset: isize) {
+        let last = self.paths.len() as isize - 1;
+        self.current = (self.current as isize + offset).clamp(0, last) as usize;
+    }
+
+    fn save(&mut self, path: &str) -> Result<(), error::Error> {
+        self.ocr_plan.save(path)?;
+        self.modified = false;
+        self.status = format!("`{}` written", path);
+        Ok(())
+    }
+
+    fn draw(&mut self, out: &mut Stdout) -> Result<(), error::Error> {
+        let (columns, rows) = terminal::size()?;
+        queue!(out, ResetColor, Clear(ClearType::All), MoveTo(0, 0))?;
+
+        let record = self.record().clone();
+        queue!(
+            out,
+            Print(format!(
+                "Page {}/{} {}{}",
+                self.current + 1,
+                self.paths.len(),
+                record.path,
+                if self.modified { " [modified]" } else { "" }
+            )),
+            MoveTo(0, 1),
+            SetForegroundColor(class_color(record.class)),
+            Print(record.class),
+            ResetColor,
+            Print(if record.user_override {
+                " (edited)"
+            } else {
+                ""
+            }),
+        )?;
+        if let Some(original) = &record.duplicate_of {
+            queue!(out, Print(format!(", duplicate of {}", original)))?;
+        }
+        if let Some(degrees) = record.rotation {
+            queue!(out, Print(format!(", rotated {}°", degrees)))?;
+        }
+        if let Some(stats) = &record.stats {
+            queue!(
+                out,
+                MoveTo(0, 2),
+                Print(format!(
+                    "{}x{}, histogram median {}, mean luma {}, ink {}%, colour {}%",
+                    stats.width,
+                    stats.height,
+                    stats.histogram_median,
+                    stats.mean_luma,
+                    stats.ink_ratio,
+                    stats.color_ratio
+                ))
+            )?;
+            if let Some(thresholds) = self.ocr_plan.thresholds() {
+                queue!(
+                    out,
+                    MoveTo(0, 3),
+                    Print(format!(
+                        "margins: image {:+.0}%, empty {:+.0}%, colour {:+.0}%",
+                        report::margin(stats.histogram_median as f64, thresholds.image as f64)
+                            * 100.0,
+                        report::margin(stats.ink_ratio, thresholds.empty) * 100.0,
+                        report::margin(stats.color_ratio, thresholds.color) * 100.0
+                    ))
+                )?;
+            }
+        }
+
+        let preview_rows = rows.saturating_sub(HEADER_LINES + FOOTER_LINES);
+        if !supports_preview() {
+            queue!(
+                out,
+                MoveTo(0, HEADER_LINES),
+                Print("(no preview, the terminal does not support 24 bit colours)")
+            )?;
+        } else if preview_rows > 0 {
+            let key = (self.current, columns, preview_rows);
+            if self.preview_key != Some(key) {
+                self.preview = preview(&record, columns, preview_rows);
+                self.preview_key = Some(key);
+            }
+            match &self.preview {
+                Some(image) => draw_preview(out, image, HEADER_LINES)?,
+                None => queue!(
+                    out,
+                    MoveTo(0, HEADER_LINES),
+                    Print("(could not read the page)")
+                )?,
+            }
+        }
+
+        queue!(
+            out,
+            ResetColor,
+            MoveTo(0, rows.saturating_sub(1)),
+            Print(if self.status.is_empty() {
+                HELP
+            } else {
+                &self.status
+            })
+        )?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+// Each cell shows two pixels: the upper one as foreground of "▀", the lower one as background
+fn draw_preview(out: &mut Stdout, image: &RgbImage, top: u16) -> Result<(), error::Error> {
+    for cell_row in 0..image.height().div_ceil(2) {
+        queue!(out, MoveTo(0, top + cell_row as u16))?;
+        for x in 0..image.width() {
+            let upper = image.get_pixel(x, cell_row * 2);
+            let lower = if cell_row * 2 + 1 < image.height() {
+                image.get_pixel(x, cell_row * 2 + 1)
+            } else {
+                upper
+            };
+            queue!(
+                out,
+                SetForegroundColor(Color::Rgb {
+                    r: upper[0],
+                    g: upper[1],
+                    b: upper[2]
+                }),
+                SetBackgroundColor(Color::Rgb {
+                    r: lower[0],
+                    g: lower[1],
+                    b: lower[2]
+                }),
+                Print('\u{2580}')
+            )?;
+        }
+        queue!(out, ResetColor)?;
+    }
+    Ok(())
+}
+
+/// Step through the pages of a plan and correct their class.
+///
+/// Pages are shown in reading order with their class, statistics and distance
+/// to the thresholds, and a preview on truecolor terminals. One key sets the
+/// class, marks the page as edited and moves to the next page. The plan is
+/// written back to `path` on `w` and `q`, `Esc` quits without writing.
+pub fn review(path: &str) -> Result<(), error::Error> {
+    let ocr_plan = OcrPlan::load(path);
+    let paths: Vec<String> = ocr_plan
+        .ordered_records()
+        .into_iter()
+        .map(|record| record.path.clone())
+        .collect();
+    if paths.is_empty() {
+        println!("`{}` has no pages to review", path);
+        return Ok(());
+    }
+    let mut reviewer = Reviewer {
+        ocr_plan,
+        paths,
+        current: 0,
+        preview_key: None,
+        preview: None,
+        modified: false,
+        status: String::new(),
+    };
+
+    let mut screen = Screen::enter()?;
+    loop {
+        reviewer.draw(&mut screen.out)?;
+        let key = match event::read()? {
+            Event::Key(KeyEvent {
+                kind: KeyEventKind::Release,
+                ..
+            }) => continue,
+            Event::Key(key) => key,
+            // redrawn at the new size
+            _ => continue,
+        };
+        reviewer.status.clear();
+        match key.code {
+            KeyCode::Char('t') => reviewer.reclassify(PageClass::Text),
+            KeyCode::Char('i') => reviewer.reclassify(PageClass::Image),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+            KeyCode::Char('c') => reviewer.reclassify(PageClass::Color),
+            KeyCode::Char('e') => reviewer.reclassify(PageClass::Empty),
+            KeyCode::Char('x') => reviewer.reclassify(PageClass::Ignore),
+            KeyCode::Right | KeyCode::Down | KeyCode::Char('j') | KeyCode::Char(' ') => {
+                reviewer.step(1)
+            }
+            KeyCode::Left | KeyCode::Up | KeyCode::Char('k') => reviewer.step(-1),
+            KeyCode::PageDown => reviewer.step(10),
+            KeyCode::PageUp => reviewer.step(-10),
+            KeyCode::Home => reviewer.current = 0,
+            KeyCode::End => reviewer.current = reviewer.paths.len() - 1,
+            KeyCode::Char('w') => reviewer.save(path)?,
+            KeyCode::Char('q') => {
+                reviewer.save(path)?;
+                break;
+            }
+            KeyCode::Esc => break,
+            _ => (),
+        }
+    }
+    drop(screen);
+
+    if reviewer.modified {
+        println!("Quit without writing the changes to `{}`", path);
+    }
+    Ok(())
+}
@@ -0,0 +1,237 @@
+use image::{DynamicImage, GenericImageView};
+use log::debug;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::librote::error;
+use crate::librote::preprocess;
+use crate::librote::Spread;
+
+// A page wider than this ratio (width / height) is treated as a two-page spread
+const SPREAD_ASPECT_RATIO: f64 = 1.2;
+// The gutter is searched within this fraction of the width around the centre
+const GUTTER_SEARCH_BAND: f64 = 0.1;
+// Columns below this fraction of the band's 90th percentile activity are blank or shadow
+const GUTTER_QUIET_RATIO: f64 = 0.2;
+
+pub fn is_spread(width: u32, height: u32) -> bool {
+    width as f64 > height as f64 * SPREAD_ASPECT_RATIO
+}
+
+/// Find the x coordinate of the gutter of a spread.
+///
+/// Text makes the luma of a column change from row to row, while the blank gap
+/// of a digital spread and the smooth binding shadow of a physical scan do not.
+/// The gutter is the centre of the widest run of such quiet columns around the
+/// centre, the run closest to the centre winning ties.
+pub fn find_gutter(image: &DynamicImage) -> u32 {
+    let luma = image.to_luma8();
+    let (width, height) = luma.dimensions();
+    let start = (width as f64 * (0.5 - GUTTER_SEARCH_BAND)) as u32;
+    let end = (width as f64 * (0.5 + GUTTER_SEARCH_BAND)) as u32;
+    if start >= end || height < 2 {
+        return width / 2;
+    }
+
+    // mean vertical luma change of each column
+    let activity: Vec<f64> = (start..end)
+        .map(|x| {
+            (1..height)
+                .map(|y| {
+                    (luma.get_pixel(x, y)[0] as f64 - luma.get_pixel(x, y - 1)[0] as f64).abs()
+                })
+                .sum::<f64>()
+                / (height - 1) as f64
+        })
+        .collect();
+
+    let mut sorted = activity.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let level = sorted[sorted.len() * 9 / 10] * GUTTER_QUIET_RATIO;
+
+    // widest run of quiet columns, the one closest to the centre on ties
+    let centre = activity.len() as f64 / 2.0;
+    let mut best: Option<(usize, usize)> = None;
+    let mut run_start = None;
+    for i in 0..=activity.len() {
+        let quiet = i < activity.len() && activity[i] <= level;
+        match (quiet, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(first)) => {
+                run_start = None;
+                let better = match best {
+                    None => true,
+                    Some((best_first, best_end)) => {
+                        let distance = |a: usize, b: usize| ((a + b) as f64 / 2.0 - centre).abs();
+                        i - first > best_end - best_first
+                            || (i - first == best_end - best_first
+                                && distance(first, i) < distance(best_first, best_end))
+                    }
+                };
+                if better {
+                    best = Some((first, i));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match best {
+        Some((first, last)) => {
+            debug!(
+                "Gutter: quiet columns {}..{} below activity {:.2}",
+                start + first as u32,
+                start + last as u32,
+                level
+            );
+            start + ((first + last) / 2) as u32
+        }
+        None => width / 2,
+    }
+}
+
+/// Name of the pages split from `path`, its path in the book without the extension.
+///
+/// `sub/p1.jpg` becomes `sub_p1`, so that spreads of different directories
+/// do not share a name.
+pub fn spread_name(path: &Path, book: &Path) -> String {
+    let relative = path.strip_prefix(book).unwrap_or(path).with_extension("");
+    relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Names of the spreads split so far, split pages are written in parallel and
+/// must not overwrite each other.
+#[derive(Default)]
+pub struct SpreadNames(Mutex<HashMap<String, String>>);
+
+impl SpreadNames {
+    /// Take the name of the spread `path`, failing when another spread of the
+    /// book already has it.
+    ///
+    /// `a_b/c.jpg` and `a/b_c.jpg`, or `p1.jpg` and `p1.jpeg`, share a name.
+    pub fn claim(&self, path: &Path, book: &Path) -> Result<String, error::Error> {
+        let name = spread_name(path, book);
+        let path = String::from(path.to_str().unwrap());
+        let mut names = self.0.lock().unwrap();
+        match names.get(&name) {
+            Some(other) => Err(error::Error::NameCollisionErr(other.clone(), path, name)),
+            None => {
+                names.insert(name.clone(), path);
+                Ok(name)
+            }
+        }
+    }
+}
+
+/// Split a spread at its gutter and write both pages to `output_dir`.
+///
+/// Pages are returned in right-to-left (Japanese) reading order, the right half
+/// is `<name>_1` and the left half is `<name>_2`. They keep the resolution `dpi`
+/// of the spread.
+pub fn split_spread(
+    path: &Path,
+    name: &str,
+    image: &DynamicImage,
+    dpi: Option<f64>,
+    output_dir: &str,
+) -> Result<(Spread, Vec<(PathBuf, DynamicImage)>), error::Error> {
+    let (width, height) = image.dimensions();
+    let gutter = find_gutter(image);
+    debug!("Splitting {:?} at x = {}", path.display(), gutter);
+
+    fs::create_dir_all(output_dir)?;
+    let extension = preprocess::output_extension(path);
+
+    let halves = vec![
+        image.crop_imm(gutter, 0, width - gutter, height),
+        image.crop_imm(0, 0, gutter, height),
+    ];
+    let mut pages = Vec::new();
+    for (index, half) in halves.into_iter().enumerate() {
+        let page_path = Path::new(output_dir).join(format!("{}_{}.{}", name, index + 1, extension));
+        preprocess::save_image(&half, &page_path, dpi)?;
+        pages.push((page_path, half));
+    }
+
+    let spread = Spread {
+        source: String::from(path.to_str().unwrap()),
+        gutter,
+        pages: pages
+            .iter()
+            .map(|(page_path, _)| String::from(page_path.to_str().unwrap()))
+            .collect(),
+    };
+    Ok((spread, pages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    // lines of words of varying width over `left..right`
+    fn write_text(image: &mut GrayImage, left: u32, right: u32) {
+        let mut seed = 12345u32;
+        for line in (100..image.height() - 100).step_by(24) {
+            let mut x = left;
+            while x < right {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let word = 20 + (seed >> 16) % 60;
+                for wx in x..(x + word).min(right) {
+                    for y in line..line + 12 {
+                        image.put_pixel(wx, y, Luma([20]));
+                    }
+                }
+                x += word + 8 + (seed >> 8) % 10;
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_spreads_split_to_the_same_pages() {
+        let book = Path::new("book");
+        let names = SpreadNames::default();
+        assert_eq!(names.claim(Path::new("book/a/b.jpg"), book).unwrap(), "a_b");
+        assert_eq!(names.claim(Path::new("book/b.jpg"), book).unwrap(), "b");
+        assert!(names.claim(Path::new("book/a_b.png"), book).is_err());
+    }
+
+    #[test]
+    fn finds_the_blank_gap_of_a_digital_spread() {
+        let mut image = GrayImage::from_pixel(2000, 1000, Luma([255]));
+        write_text(&mut image, 100, 980);
+        write_text(&mut image, 1020, 1900);
+        let gutter = find_gutter(&DynamicImage::ImageLuma8(image));
+        assert!((980..1020).contains(&gutter), "gutter at {}", gutter);
+    }
+
+    #[test]
+    fn finds_the_binding_shadow_of_a_scan() {
+        let mut image = GrayImage::from_pixel(2000, 1000, Luma([230]));
+        write_text(&mut image, 100, 1040);
+        write_text(&mut image, 1120, 1900);
+        for x in 1040..1120 {
+            let shade = 230 - (150 - (x as i32 - 1080).abs() * 3) as u8;
+            for y in 0..1000 {
+                image.put_pixel(x, y, Luma([shade]));
+            }
+        }
+        let gutter = find_gutter(&DynamicImage::ImageLuma8(image));
+        assert!((1040..1120).contains(&gutter), "gutter at {}", gutter);
+    }
+
+    #[test]
+    fn falls_back_to_the_centre_of_a_blank_spread() {
+        let image = GrayImage::from_pixel(2000, 1000, Luma([255]));
+        assert_eq!(find_gutter(&DynamicImage::ImageLuma8(image)), 1000);
+    }
+}
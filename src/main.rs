@@ -8,15 +8,19 @@ use fern::colors::{Color, ColoredLevelConfig};
 use fs2::FileExt;
 use log::{debug, info, LevelFilter};
 use std::fs::OpenOptions;
-use std::io::{stdout, Write};
-use std::path::PathBuf;
+use std::io::stdout;
+use std::path::{Path, PathBuf};
+use std::thread;
 use std::unreachable;
 
 mod librote;
-use librote::{epub_gen, gdrive, pdf, plan, process};
+use librote::chunks::{ChunkManifest, CHUNKS_PATH};
+use librote::{
+    epub_gen, extract, filter, gdrive, marker, model, pdf, plan, preprocess, process, report,
+    review, OcrPlan, OCR_PLAN_PATH,
+};
 
 pub const PROGRAM_NAME: &str = "rote";
-const MAGIC_THRESHOLD_MEAN_NUMBER: u32 = 750;
 
 fn setup_logging(verbosity: u64, chain: bool, log_path: Option<&str>) -> Result<Option<&str>> {
     let colors_line = ColoredLevelConfig::new()
@@ -93,6 +97,12 @@ fn setup_logging(verbosity: u64, chain: bool, log_path: Option<&str>) -> Result<
     Ok(log_path)
 }
 
+fn expand_path(path: &str) -> String {
+    shellexpand::full(path)
+        .expect("Could not find the correct path")
+        .into_owned()
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     let matches = cli_interface();
@@ -117,43 +127,189 @@ async fn main() -> Result<()> {
     debug!("-----Logger is initialized. Starting main program!-----");
 
     match matches.subcommand() {
+        Some(("plan", plan_matches)) if plan_matches.subcommand_name() == Some("review") => {
+            let review_matches = plan_matches.subcommand_matches("review").unwrap();
+            review::review(review_matches.value_of("plan").unwrap_or(OCR_PLAN_PATH))?;
+        }
+        Some(("plan", plan_matches)) if plan_matches.subcommand_name() == Some("learn") => {
+            let learn_matches = plan_matches.subcommand_matches("learn").unwrap();
+            let plan_path = learn_matches.value_of("plan").unwrap_or(OCR_PLAN_PATH);
+            let model_path = expand_path(
+                learn_matches
+                    .value_of("model")
+                    .unwrap_or(model::DEFAULT_MODEL_PATH),
+            );
+            let mut classifier_model = model::Model::load(&model_path);
+            let learned = classifier_model.learn(&OcrPlan::load(plan_path));
+            classifier_model.save(&model_path)?;
+            println!("Learned {} page(s) of `{}`", learned, plan_path);
+            for (class, count) in classifier_model.counts() {
+                println!("{}: {} page(s)", class, count);
+            }
+        }
         Some(("plan", plan_matches)) => {
-            let input = plan_matches.value_of("input").unwrap();
-            let image_threadhold = value_t!(plan_matches, "image-threadhold", u32)
-                .unwrap_or(MAGIC_THRESHOLD_MEAN_NUMBER);
-            let empty_page_threadhold =
-                value_t!(plan_matches, "empty-threadhold", u32).unwrap_or(0);
+            // a PDF is extracted to an image directory first
+            let input = extract::resolve_input(plan_matches.value_of("input").unwrap())?;
+            // explicit threadholds override the calibrated ones
+            let image_threadhold = value_t!(plan_matches, "image-threadhold", u32).ok();
+            if plan_matches.is_present("empty-threadhold") {
+                // it was a luma value, a number that still parses would silently mean another thing
+                anyhow::bail!(
+                    "`--empty-threadhold` is no longer supported, empty pages are found from their ink, use `--empty-ink-ratio`"
+                );
+            }
+            let empty_page_threadhold = value_t!(plan_matches, "empty-ink-ratio", f64)
+                .unwrap_or(plan::DEFAULT_EMPTY_INK_RATIO);
+            let color_threadhold = value_t!(plan_matches, "color-threadhold", f64)
+                .unwrap_or(plan::DEFAULT_COLOR_THRESHOLD);
+
+            let spread_dir = plan_matches.value_of("split-spreads").map(String::from);
+            let layout = plan_matches
+                .value_of("layout")
+                .map(|layout| layout.parse().expect("Could not parse value of `layout`"));
+            let jobs = value_t!(plan_matches, "jobs", usize)
+                .unwrap_or_else(|_| thread::available_parallelism().map_or(1, |jobs| jobs.get()));
 
             debug!(
-                "image_threadhold = {}, empty_threadhold = {}",
-                image_threadhold, empty_page_threadhold
+                "image_threadhold = {:?}, empty_threadhold = {}, color_threadhold = {}, spread_dir = {:?}, jobs = {}",
+                image_threadhold, empty_page_threadhold, color_threadhold, spread_dir, jobs
             );
 
-            let ocr_plan = plan::plan(input, image_threadhold, empty_page_threadhold)
-                .expect("Could not generate a plan");
+            let classifier = if plan_matches.is_present("no-model") {
+                None
+            } else {
+                let model_path = expand_path(
+                    plan_matches
+                        .value_of("model")
+                        .unwrap_or(model::DEFAULT_MODEL_PATH),
+                );
+                model::Model::load(&model_path).classifier()
+            };
+
+            let options = plan::PlanOptions {
+                image_threadhold,
+                empty_page_threadhold,
+                color_threadhold,
+                spread_dir,
+                jobs,
+                recursive: plan_matches.is_present("recursive"),
+                layout,
+                classifier,
+            };
+            let (ocr_plan, thumbnails) =
+                plan::plan(&input, &options).expect("Could not generate a plan");
+
+            let ocr_plan = if plan_matches.is_present("update") && Path::new(OCR_PLAN_PATH).exists()
+            {
+                let old_plan = OcrPlan::load(OCR_PLAN_PATH);
+                let merged_plan = old_plan.merge(&ocr_plan);
+                let changes = old_plan.diff(&merged_plan);
+                for change in &changes {
+                    println!("{}", change);
+                }
+                merged_plan.save(OCR_PLAN_PATH)?;
+                debug!("OCR plan merged into `{}`", OCR_PLAN_PATH);
+                println!(
+                    "`{}` file updated with {} change(s)",
+                    OCR_PLAN_PATH,
+                    changes.len()
+                );
+                merged_plan
+            } else {
+                ocr_plan.save(OCR_PLAN_PATH)?;
+                debug!("OCR plan written to `{}`", OCR_PLAN_PATH);
+                println!(
+                    "`{}` file created. Now edit this file to proceed further",
+                    OCR_PLAN_PATH
+                );
+                ocr_plan
+            };
 
-            let mut ocr_plan_file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open("ocr_plan.toml")
-                .unwrap();
-            write!(ocr_plan_file, "{}", ocr_plan)?;
-            debug!("OCR plan written to `ocr_plan.toml`");
-            println!("`ocr_plan.toml` file created. Now edit this file to proceed further");
+            report::write_report(&ocr_plan, report::REPORT_PATH, jobs, &thumbnails)?;
+            println!(
+                "Open `{}` to review the classification",
+                report::REPORT_PATH
+            );
+        }
+        Some(("preprocess", preprocess_matches)) => {
+            let output_dir = preprocess_matches.value_of("output").unwrap();
+            let max_skew_angle = value_t!(preprocess_matches, "max-skew-angle", f64)
+                .unwrap_or(preprocess::DEFAULT_MAX_SKEW_ANGLE);
+            let jobs = value_t!(preprocess_matches, "jobs", usize)
+                .unwrap_or_else(|_| thread::available_parallelism().map_or(1, |jobs| jobs.get()));
+            let crop_padding = value_t!(preprocess_matches, "crop-padding", u32)
+                .unwrap_or(preprocess::DEFAULT_CROP_PADDING);
+            let filters = preprocess_matches.value_of("filters").map(|chain| {
+                filter::parse_chain(chain).expect("Could not parse value of `filters`")
+            });
+            let options = preprocess::PreprocessOptions {
+                output_dir: String::from(output_dir),
+                rotate: preprocess_matches.is_present("rotate"),
+                deskew: preprocess_matches.is_present("deskew"),
+                max_skew_angle,
+                crop: preprocess_matches.is_present("crop"),
+                crop_padding,
+                illustrations: preprocess_matches.is_present("illustrations"),
+                filters,
+                jobs,
+            };
+            preprocess::preprocess(&options)?;
+            println!(
+                "Preprocessed pages written to `{}` and recorded in `{}`",
+                output_dir, OCR_PLAN_PATH
+            );
         }
         Some(("ocr", ocr_matches)) => {
-            let input = ocr_matches.value_of("input").unwrap();
+            let input = extract::resolve_input(ocr_matches.value_of("input").unwrap())?;
             let parent_id = ocr_matches.value_of("id").unwrap();
-            let num_chunk = pdf::gen_pdf(input)?;
-            gdrive::upload_pdf("rote_client_secret.json", parent_id, num_chunk).await?;
+            let jobs = value_t!(ocr_matches, "jobs", usize)
+                .unwrap_or_else(|_| thread::available_parallelism().map_or(1, |jobs| jobs.get()));
+            let options = pdf::PdfOptions {
+                paper: ocr_matches
+                    .value_of("paper")
+                    .map(|paper| paper.parse().expect("Could not parse value of `paper`")),
+                dpi: ocr_matches
+                    .value_of("dpi")
+                    .map(|dpi| pdf::parse_dpi(dpi).expect("Could not parse value of `dpi`")),
+                jobs,
+            };
+            let manifest = pdf::gen_pdf(&input, &options)?;
+            manifest.save(CHUNKS_PATH)?;
+            println!(
+                "{} chunk(s) written and recorded in `{}`",
+                manifest.chunks().len(),
+                CHUNKS_PATH
+            );
+            gdrive::upload_pdf("rote_client_secret.json", parent_id, &manifest).await?;
         }
         Some(("process", process_matches)) => {
-            let num_chunk =
-                value_t!(process_matches, "input", u8).expect("Could not parse value of `input`");
+            let manifest = ChunkManifest::load(process_matches.value_of("chunks").unwrap());
             let font_size_threadhold =
                 value_t!(process_matches, "font-size-threadhold", u8).unwrap_or(10);
-            process::tidy(num_chunk);
-            process::parse_ocr_html(num_chunk, font_size_threadhold);
+            process::tidy(&manifest);
+            let missing = process::parse_ocr_html(&manifest, font_size_threadhold);
+            let pages: usize = manifest
+                .chunks()
+                .iter()
+                .map(|chunk| chunk.pages.len())
+                .sum();
+            if missing.is_empty() {
+                println!("The markers of all {} pages were found", pages);
+            } else {
+                println!(
+                    "{} of {} page markers were not found, the text of these pages may be missing or merged with the next page:",
+                    missing.len(),
+                    pages
+                );
+                for (chunk, page) in &missing {
+                    println!(
+                        "  chunk {:02}: `{}` ({})",
+                        chunk,
+                        page.path,
+                        marker::text(page.marker)
+                    );
+                }
+            }
         }
         Some(("epub", epub_matches)) => {
             let plan_path = epub_matches.value_of("plan").unwrap();
@@ -198,25 +354,174 @@ fn cli_interface() -> ArgMatches {
         .subcommand(
             Command::new("plan")
                 .about("Create a ocr plan")
+                .subcommand_negates_reqs(true)
+                .subcommand(
+                    Command::new("learn")
+                        .about("Learn the corrected classification of a plan, for the classifier of later plans")
+                        .arg(
+                            Arg::new("plan")
+                                .help("Plan file, default ocr_plan.toml")
+                                .index(1)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::new("model")
+                                .help("Model file, default ~/.local/share/rote/model.toml")
+                                .short('m')
+                                .long("model")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    Command::new("review")
+                        .about("Review the plan page by page and correct the classification")
+                        .arg(
+                            Arg::new("plan")
+                                .help("Plan file, default ocr_plan.toml")
+                                .index(1)
+                                .takes_value(true),
+                        ),
+                )
                 .arg(
                     Arg::new("input")
-                        .help("Input directory")
+                        .help("Input directory, archive (zip, cbz, tar) or scanned PDF")
                         .index(1)
                         .takes_value(true)
                         .required(true),
                 )
                 .arg(
                     Arg::new("image-threadhold")
-                        .help("Input threadhold number for image")
+                        .help("Input threadhold number for image, calibrated if not given")
                         .short('i')
                         .long("image-threadhold")
                         .takes_value(true),
                 )
                 .arg(
                     Arg::new("empty-threadhold")
-                        .help("Input threadhold number for empty page")
+                        .help("No longer supported, use `--empty-ink-ratio`")
                         .short('e')
                         .long("empty-threadhold")
+                        .takes_value(true)
+                        .hide(true),
+                )
+                .arg(
+                    Arg::new("empty-ink-ratio")
+                        .help("Percentage of ink at or below which a page is empty, default 0.05")
+                        .long("empty-ink-ratio")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("color-threadhold")
+                        .help("Percentage of coloured pixels for a colour image, default 5")
+                        .short('c')
+                        .long("color-threadhold")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("split-spreads")
+                        .help("Split two-page spreads into pages written to this directory")
+                        .short('s')
+                        .long("split-spreads")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .help("Number of pages analysed in parallel, default all cores")
+                        .short('j')
+                        .long("jobs")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("layout")
+                        .help("Direction of the text lines, to find rotated pages, detected if not given")
+                        .short('l')
+                        .long("layout")
+                        .takes_value(true)
+                        .possible_values(["horizontal", "vertical"]),
+                )
+                .arg(
+                    Arg::new("recursive")
+                        .help("Also take the pages of subdirectories, in natural order of their paths")
+                        .short('r')
+                        .long("recursive"),
+                )
+                .arg(
+                    Arg::new("model")
+                        .help("Model learned by `plan learn`, default ~/.local/share/rote/model.toml")
+                        .short('m')
+                        .long("model")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("no-model")
+                        .help("Classify with the threadholds even when a model was learned")
+                        .long("no-model"),
+                )
+                .arg(
+                    Arg::new("update")
+                        .help("Merge with the existing plan, only classify pages not in it yet")
+                        .short('u')
+                        .long("update"),
+                ),
+        )
+        .subcommand(
+            Command::new("preprocess")
+                .about("Normalize the text pages of the ocr plan before creating pdf files")
+                .arg(
+                    Arg::new("output")
+                        .help("Output directory for the normalized pages")
+                        .index(1)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("rotate")
+                        .help("Turn the pages the plan flags as rotated upright")
+                        .short('r')
+                        .long("rotate"),
+                )
+                .arg(
+                    Arg::new("deskew")
+                        .help("Estimate and correct the skew angle of each page")
+                        .short('d')
+                        .long("deskew"),
+                )
+                .arg(
+                    Arg::new("max-skew-angle")
+                        .help("Largest skew angle searched in degrees, default 5")
+                        .long("max-skew-angle")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("crop")
+                        .help("Crop scanner borders and excess margin, crop boxes already in the plan are kept unless the page is now turned another way")
+                        .short('c')
+                        .long("crop"),
+                )
+                .arg(
+                    Arg::new("crop-padding")
+                        .help("Pixels kept around the content when cropping, default 32")
+                        .long("crop-padding")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("filters")
+                        .help("Filters applied in order to text pages, among grayscale, contrast, sauvola and despeckle, comma separated or `none`. Kept in the plan for the book, a page `filters` overrides it")
+                        .short('f')
+                        .long("filters")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("illustrations")
+                        .help("Crop the illustrations of text pages to their own image and blank them out, illustrations already in the plan are applied with or without it")
+                        .short('i')
+                        .long("illustrations"),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .help("Number of pages processed in parallel, default all cores")
+                        .short('j')
+                        .long("jobs")
                         .takes_value(true),
                 ),
         )
@@ -225,7 +530,7 @@ fn cli_interface() -> ArgMatches {
                 .about("Start creating pdf files, OCR them and output raw html result")
                 .arg(
                     Arg::new("input")
-                        .help("Input directory")
+                        .help("Input directory, archive (zip, cbz, tar) or scanned PDF")
                         .index(1)
                         .takes_value(true)
                         .required(true),
@@ -236,17 +541,38 @@ fn cli_interface() -> ArgMatches {
                         .index(2)
                         .takes_value(true)
                         .required(true),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .help("Number of pages encoded in parallel, default all cores")
+                        .short('j')
+                        .long("jobs")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("paper")
+                        .help("Put every page on this paper (a4, a5, a6, b5, b6, letter or WIDTHxHEIGHT in mm) instead of sizing it from its image")
+                        .short('p')
+                        .long("paper")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("dpi")
+                        .help("Resolution of the pages between 100 and 1200, default the one they declare or 300")
+                        .short('d')
+                        .long("dpi")
+                        .takes_value(true),
                 ),
         )
         .subcommand(
             Command::new("process")
                 .about("Process and output raw text from raw html for further editing")
                 .arg(
-                    Arg::new("input")
-                        .help("Input number of chunk")
+                    Arg::new("chunks")
+                        .help("Chunk manifest written by `ocr`")
                         .index(1)
                         .takes_value(true)
-                        .required(true),
+                        .default_value(CHUNKS_PATH),
                 )
                 .arg(
                     Arg::new("font-size-threadhold")
@@ -268,7 +594,7 @@ fn cli_interface() -> ArgMatches {
                 )
                 .arg(
                     Arg::new("input")
-                        .help("Input image path")
+                        .help("Input image directory or archive (zip, cbz, tar)")
                         .index(2)
                         .required(true)
                         .takes_value(true),